@@ -0,0 +1,111 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ferris_chat::components::Location;
+use ferris_chat::entities::spawn_crab;
+use ferris_chat::interest::entities_of_interest;
+use ferris_chat::map::{euclidean_distance, PICKUP_DISTANCE};
+use ferris_chat::prefabs::Prefabs;
+use ferris_chat::spatial::SpatialIndex;
+use ferris_chat::state::{initialize_ecs, State, WorldConfig};
+use oorandom::Rand32;
+use specs::prelude::*;
+
+const ENTITY_COUNTS: [u32; 3] = [100, 1_000, 10_000];
+
+/// Radius the server uses for interest unless told otherwise
+const INTEREST_RADIUS: f64 = 50.0;
+
+/// Side of a square holding `count` entities about as densely as a busy island does
+fn area_side(count: u32) -> i32 {
+    ((count as f64).sqrt() * 10.0) as i32
+}
+
+/// `count` entities scattered over an area that grows with them
+fn scattered_entities(count: u32) -> (World, Vec<(Entity, Location)>) {
+    let mut world = World::new();
+    let mut rng = Rand32::new(0);
+    let side = area_side(count) as u32;
+    let entities = (0..count)
+        .map(|_| {
+            let location = Location {
+                x: rng.rand_range(0..side) as i32,
+                y: rng.rand_range(0..side) as i32,
+            };
+            (world.create_entity().build(), location)
+        })
+        .collect();
+    (world, entities)
+}
+
+fn pickup_distance_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pickup_distance");
+    for count in ENTITY_COUNTS.iter() {
+        let (_world, entities) = scattered_entities(*count);
+        let mut spatial_index = SpatialIndex::default();
+        for (entity, location) in entities.iter() {
+            spatial_index.insert(*entity, location);
+        }
+        let centre = entities[0].1.clone();
+
+        group.bench_with_input(BenchmarkId::new("spatial_index", count), count, |b, _| {
+            b.iter(|| spatial_index.query_radius(&centre, PICKUP_DISTANCE))
+        });
+        group.bench_with_input(BenchmarkId::new("every_entity", count), count, |b, _| {
+            b.iter(|| {
+                entities
+                    .iter()
+                    .filter(|(_, location)| euclidean_distance(&centre, location) < PICKUP_DISTANCE)
+                    .map(|(entity, _)| *entity)
+                    .collect::<Vec<Entity>>()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn rebuilding_the_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rebuild_spatial_index");
+    for count in ENTITY_COUNTS.iter() {
+        let (_world, entities) = scattered_entities(*count);
+        let mut spatial_index = SpatialIndex::default();
+        group.bench_with_input(BenchmarkId::from_parameter(count), count, |b, _| {
+            b.iter(|| {
+                spatial_index.clear();
+                for (entity, location) in entities.iter() {
+                    spatial_index.insert(*entity, location);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn interest_management(c: &mut Criterion) {
+    let mut group = c.benchmark_group("entities_of_interest");
+    group.sample_size(20);
+    for count in ENTITY_COUNTS.iter() {
+        let config = WorldConfig {
+            width: area_side(*count).max(100),
+            height: area_side(*count).max(100),
+            ai_crabs: *count,
+            ..WorldConfig::default()
+        };
+        let mut gs = State::new();
+        initialize_ecs(&mut gs.ecs, &config, Prefabs::default());
+        spawn_crab(&mut gs.ecs, "player-0", "Ferris", false);
+        // Build the spatial index
+        gs.tick();
+
+        group.bench_with_input(BenchmarkId::from_parameter(count), count, |b, _| {
+            b.iter(|| entities_of_interest(&gs.ecs, "player-0", INTEREST_RADIUS))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    pickup_distance_queries,
+    rebuilding_the_index,
+    interest_management
+);
+criterion_main!(benches);
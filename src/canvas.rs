@@ -1,24 +1,99 @@
 use ferris_chat::components::*;
+use ferris_chat::interpolation::SnapshotBuffer;
 use ferris_chat::map::{Map, TileType};
 use specs::prelude::*;
+use specs::saveload::{Marker, SimpleMarker};
+use std::collections::HashMap;
 use stdweb::traits::*;
 use stdweb::unstable::TryInto;
 use stdweb::web::html_element::CanvasElement;
 use stdweb::web::{document, CanvasRenderingContext2d, FillRule};
 
-pub struct DrawSystem {}
+/// How see-through a dead crab is
+const DEAD_ALPHA: f64 = 0.3;
+/// How see-through a crab waiting for its player to reconnect is
+const IDLE_ALPHA: f64 = 0.6;
+
+/// Drawn under crabs wading through shallow water
+fn splash() -> TextRenderable {
+    TextRenderable {
+        text: String::from("💦"),
+        font_size: 15_f64,
+        offset_x: -0.5_f64,
+        offset_y: 1_f64,
+    }
+}
+
+/// Where equipped items are drawn relative to whoever's wearing or holding them, so every
+/// hat sits on top of the crab however it looks on the ground
+fn slot_offset(slot: EquipmentSlot) -> (f64, f64) {
+    match slot {
+        EquipmentSlot::Head => (0_f64, -2_f64),
+        EquipmentSlot::Face => (0.2_f64, 1.9_f64),
+        EquipmentSlot::Hand => (2.5_f64, 2_f64),
+    }
+}
+
+/// Where to draw everything with a location, in (fractional) tiles. Our own crab is drawn
+/// wherever we've predicted it to be and other entities somewhere between the last two
+/// snapshots, while carried things stay with whoever's carrying them.
+fn draw_positions(
+    now: f64,
+    player_id: &String,
+    entities: &Entities,
+    snapshot_buffer: &SnapshotBuffer,
+    markers: &ReadStorage<SimpleMarker<EntityMarker>>,
+    locations: &ReadStorage<Location>,
+    player_infos: &ReadStorage<PlayerInfo>,
+    carried_bys: &ReadStorage<CarriedBy>,
+) -> HashMap<Entity, (f64, f64)> {
+    let mut positions = HashMap::new();
+    for (entity, location) in (entities, locations).join() {
+        let ours = player_infos
+            .get(entity)
+            .map_or(false, |player_info| player_info.id == *player_id);
+        let interpolated = match markers.get(entity) {
+            Some(marker) if !ours => snapshot_buffer.position(marker.id(), now),
+            _ => None,
+        };
+        positions.insert(
+            entity,
+            interpolated.unwrap_or((location.x as f64, location.y as f64)),
+        );
+    }
+    for (entity, carried_by) in (entities, carried_bys).join() {
+        if let Some(owner_position) = positions.get(&carried_by.owner).cloned() {
+            positions.insert(entity, owner_position);
+        }
+    }
+    positions
+}
+
+pub struct DrawSystem {
+    /// Milliseconds, from the same clock snapshots are buffered with
+    pub now: f64,
+}
 
 impl<'a> System<'a> for DrawSystem {
     type SystemData = (
         Entities<'a>,
         ReadExpect<'a, Canvas>,
         ReadExpect<'a, Map>,
+        ReadExpect<'a, String>,
+        ReadExpect<'a, SnapshotBuffer>,
+        ReadStorage<'a, SimpleMarker<EntityMarker>>,
+        ReadStorage<'a, PlayerInfo>,
+        ReadStorage<'a, CarriedBy>,
         ReadStorage<'a, Location>,
         ReadStorage<'a, Renderable>,
         ReadStorage<'a, TextRenderable>,
         ReadStorage<'a, ChatRenderable>,
         ReadStorage<'a, GraphicRenderable>,
         ReadStorage<'a, Disappearing>,
+        ReadStorage<'a, Dead>,
+        ReadStorage<'a, Idle>,
+        ReadStorage<'a, StandingOn>,
+        ReadStorage<'a, Equipped>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
@@ -26,44 +101,88 @@ impl<'a> System<'a> for DrawSystem {
             entities,
             canvas,
             map,
+            player_id,
+            snapshot_buffer,
+            markers,
+            player_infos,
+            carried_bys,
             locations,
             renderable,
             text_renders,
             chat_renders,
             graphic_renders,
             disappearings,
+            deads,
+            idles,
+            standing_ons,
+            equippeds,
         ) = data;
 
         // Clear the canvas to draw again
         canvas.draw_blank_map(&map);
 
+        let positions = draw_positions(
+            self.now,
+            &player_id,
+            &entities,
+            &snapshot_buffer,
+            &markers,
+            &locations,
+            &player_infos,
+            &carried_bys,
+        );
         let mut draw_data = (&entities, &locations, &renderable)
             .join()
             .collect::<Vec<_>>();
         draw_data.sort_by(|&a, &b| b.2.render_order.cmp(&a.2.render_order));
-        for (entity, location, _renderable) in draw_data.iter() {
+        for (entity, _location, _renderable) in draw_data.iter() {
+            let position = positions[entity];
             let alpha = match disappearings.get(*entity) {
                 None => 1f64,
                 Some(disappearing) => {
                     (disappearing.ticks_left as f64 / disappearing.total_ticks as f64).min(1.0)
                 }
             };
-            match text_renders.get(*entity) {
-                None => {}
-                Some(text_render) => {
-                    canvas.draw_text(alpha, &location, &text_render);
+            // Dead crabs fade out until they respawn
+            let alpha = match deads.get(*entity) {
+                None => alpha,
+                Some(_) => alpha * DEAD_ALPHA,
+            };
+            let alpha = match idles.get(*entity) {
+                None => alpha,
+                Some(_) => alpha * IDLE_ALPHA,
+            };
+            // Crabs wading through shallow water kick up a splash
+            if let Some(standing_on) = standing_ons.get(*entity) {
+                if standing_on.tile_type == TileType::ShallowWater {
+                    canvas.draw_text(alpha, position, &splash());
+                }
+            }
+            match (text_renders.get(*entity), equippeds.get(*entity)) {
+                (None, _) => {}
+                (Some(text_render), None) => {
+                    canvas.draw_text(alpha, position, &text_render);
+                }
+                (Some(text_render), Some(equipped)) => {
+                    let (offset_x, offset_y) = slot_offset(equipped.slot);
+                    let text_render = TextRenderable {
+                        offset_x,
+                        offset_y,
+                        ..text_render.clone()
+                    };
+                    canvas.draw_text(alpha, position, &text_render);
                 }
             };
             match chat_renders.get(*entity) {
                 None => {}
                 Some(chat_render) => {
-                    canvas.draw_chat_bubble(alpha, &location, &chat_render);
+                    canvas.draw_chat_bubble(alpha, position, &chat_render);
                 }
             };
             match graphic_renders.get(*entity) {
                 None => {}
                 Some(graphic_render) => {
-                    canvas.draw_graphic(alpha, &location, &graphic_render);
+                    canvas.draw_graphic(alpha, position, &graphic_render);
                 }
             };
         }
@@ -82,6 +201,9 @@ pub struct Canvas {
 const WATER_TOP_COLOR: &str = "#67bde0";
 const WATER_LEFT_COLOR: &str = "#41add8";
 const WATER_RIGHT_COLOR: &str = "#95d1e9";
+const SHALLOW_WATER_TOP_COLOR: &str = "#8fd3ec";
+const SHALLOW_WATER_LEFT_COLOR: &str = "#67bde0";
+const SHALLOW_WATER_RIGHT_COLOR: &str = "#b5e2f3";
 const SAND_TOP_COLOR: &str = "#fae7c9";
 const SAND_LEFT_COLOR: &str = "#f4cc8a";
 const SAND_RIGHT_COLOR: &str = "#fdf5e8";
@@ -100,18 +222,26 @@ impl Canvas {
 
         let ctx: CanvasRenderingContext2d = canvas.get_context().unwrap();
 
-        let scaled_width = canvas.width() as f64 / width as f64 * 2.0;
-        // x0.99 because bottom was being trimmed and I'm a shit programmer.
-        let scaled_height = canvas.height() as f64 / height as f64 * 0.99;
-
-        Canvas {
+        let mut canvas = Canvas {
             canvas,
             ctx,
-            scaled_width,
-            scaled_height,
-            width,
-            height,
-        }
+            scaled_width: 0.0,
+            scaled_height: 0.0,
+            width: 0,
+            height: 0,
+        };
+        canvas.fit_map(width, height);
+        canvas
+    }
+
+    /// Scale tiles so a map this size fills the canvas. The server's map can be a
+    /// different size to the one we started with, so this is done again when it arrives.
+    pub fn fit_map(&mut self, width: u32, height: u32) {
+        self.scaled_width = self.canvas.width() as f64 / width as f64 * 2.0;
+        // x0.99 because bottom was being trimmed and I'm a shit programmer.
+        self.scaled_height = self.canvas.height() as f64 / height as f64 * 0.99;
+        self.width = width;
+        self.height = height;
     }
 
     pub fn convert_from_screen(
@@ -128,9 +258,11 @@ impl Canvas {
         (x, y)
     }
 
-    pub fn convert_to_isometric(&self, x: i32, y: i32) -> (f64, f64) {
-        let screen_x = (x - y) as f64 * self.scaled_width / 2.0 + (self.width as f64 * 4.0);
-        let screen_y = (x + y) as f64 * self.scaled_height / 2.0 + self.scaled_height;
+    /// Screen coordinates of a point on the map. Fractions of a tile are fine, for things on
+    /// their way from one tile to the next.
+    pub fn convert_to_isometric(&self, x: f64, y: f64) -> (f64, f64) {
+        let screen_x = (x - y) * self.scaled_width / 2.0 + (self.width as f64 * 4.0);
+        let screen_y = (x + y) * self.scaled_height / 2.0 + self.scaled_height;
 
         (screen_x, screen_y)
     }
@@ -142,6 +274,12 @@ impl Canvas {
         }
         let (height_scale, top_color, left_color, right_color) = match tile_type {
             TileType::Water => (0.25, WATER_TOP_COLOR, WATER_LEFT_COLOR, WATER_RIGHT_COLOR),
+            TileType::ShallowWater => (
+                0.125,
+                SHALLOW_WATER_TOP_COLOR,
+                SHALLOW_WATER_LEFT_COLOR,
+                SHALLOW_WATER_RIGHT_COLOR,
+            ),
             TileType::Sand => (0.0, SAND_TOP_COLOR, SAND_LEFT_COLOR, SAND_RIGHT_COLOR),
             TileType::Grass => (-0.5, GRASS_TOP_COLOR, GRASS_LEFT_COLOR, GRASS_RIGHT_COLOR),
             TileType::Void => (0.0, "", "", ""),
@@ -153,7 +291,7 @@ impl Canvas {
         let height_modifier = height_scale * self.scaled_height;
 
         // Draw the isometric tile
-        let (x, y) = self.convert_to_isometric(orig_x as i32, orig_y as i32);
+        let (x, y) = self.convert_to_isometric(orig_x as f64, orig_y as f64);
 
         // Draw the tile top
         // --------------------------------------------
@@ -259,7 +397,7 @@ impl Canvas {
     pub fn draw_graphic(
         &self,
         alpha: f64,
-        location: &Location,
+        position: (f64, f64),
         graphic_renderable: &GraphicRenderable,
     ) {
         self.ctx.set_global_alpha(alpha);
@@ -267,7 +405,7 @@ impl Canvas {
         let graphic_width = self.scaled_width * 5_f64;
         let graphic_height = self.scaled_height * 10_f64;
 
-        let (iso_x, iso_y) = self.convert_to_isometric(location.x as i32, location.y as i32);
+        let (iso_x, iso_y) = self.convert_to_isometric(position.0, position.1);
         let x = iso_x + graphic_renderable.offset_x * self.scaled_width - graphic_width / 2.0;
         let y = iso_y + graphic_renderable.offset_y * self.scaled_height - graphic_height / 2.0;
 
@@ -283,7 +421,7 @@ impl Canvas {
         self.ctx.set_global_alpha(1f64);
     }
 
-    pub fn draw_text(&self, alpha: f64, location: &Location, text_renderable: &TextRenderable) {
+    pub fn draw_text(&self, alpha: f64, position: (f64, f64), text_renderable: &TextRenderable) {
         self.ctx.set_global_alpha(alpha);
 
         let text_height = text_renderable.font_size;
@@ -295,7 +433,7 @@ impl Canvas {
             .expect("Canvas measure_text failed")
             .get_width();
 
-        let (iso_x, iso_y) = self.convert_to_isometric(location.x as i32, location.y as i32);
+        let (iso_x, iso_y) = self.convert_to_isometric(position.0, position.1);
         let x = iso_x - (text_width / 2_f64) + text_renderable.offset_x * self.scaled_width;
         let y = iso_y + text_renderable.offset_y * self.scaled_height;
 
@@ -309,11 +447,11 @@ impl Canvas {
     pub fn draw_chat_bubble(
         &self,
         alpha: f64,
-        location: &Location,
+        position: (f64, f64),
         chat_renderable: &ChatRenderable,
     ) {
         self.ctx.set_font("20px helvetica");
-        let (iso_x, iso_y) = self.convert_to_isometric(location.x as i32, location.y as i32);
+        let (iso_x, iso_y) = self.convert_to_isometric(position.0, position.1);
         let x = iso_x as f64 + chat_renderable.offset_x * self.scaled_width;
         let y = iso_y as f64 + (1f64 + chat_renderable.offset_y) * self.scaled_height;
         let w = self
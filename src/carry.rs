@@ -1,5 +1,9 @@
-use crate::components::{CarriedBy, Location, PlayerInfo, Renderable, WantsToBePickedUp};
-use crate::map::within_pickup_distance;
+use crate::components::{
+    AutoPickup, CarriedBy, Dead, EquipmentSlot, Equipped, Item, Location, PlayerInfo, Renderable,
+    WantsToBePickedUp,
+};
+use crate::map::PICKUP_DISTANCE;
+use crate::spatial::SpatialIndex;
 use specs::prelude::*;
 
 pub struct CarrySystem {}
@@ -31,48 +35,227 @@ impl<'a> System<'a> for CarrySystem {
     }
 }
 
+/// How long a dropped item stays on the ground before anyone can grab it
+pub const DROP_PICKUP_DELAY_TICKS: u32 = 20;
+
+/// Most items a crab can carry around in its inventory, not counting what it has equipped
+pub const INVENTORY_SIZE: usize = 4;
+
+/// Where an item goes when a crab takes it
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Stow {
+    Equip(EquipmentSlot),
+    Inventory,
+}
+
+/// The item `player_entity` has in `slot`, if any
+pub fn equipped_in(
+    player_entity: Entity,
+    slot: EquipmentSlot,
+    entities: &Entities,
+    carried_bys: &WriteStorage<CarriedBy>,
+    equippeds: &WriteStorage<Equipped>,
+) -> Option<Entity> {
+    (entities, carried_bys, equippeds)
+        .join()
+        .find(|(_, carried_by, equipped)| {
+            carried_by.owner == player_entity && equipped.slot == slot
+        })
+        .map(|(item_entity, _, _)| item_entity)
+}
+
+/// How many items `player_entity` has stowed away in its inventory
+pub fn inventory_count(
+    player_entity: Entity,
+    carried_bys: &WriteStorage<CarriedBy>,
+    items: &ReadStorage<Item>,
+    equippeds: &WriteStorage<Equipped>,
+) -> usize {
+    (carried_bys, items, !equippeds)
+        .join()
+        .filter(|(carried_by, _, _)| carried_by.owner == player_entity)
+        .count()
+}
+
+/// Work out where `player_entity` could put `item`: straight into its slot if that's
+/// free, otherwise into the inventory if there's room. None if the crab's hands are full.
+pub fn find_room(
+    player_entity: Entity,
+    item: &Item,
+    entities: &Entities,
+    carried_bys: &WriteStorage<CarriedBy>,
+    items: &ReadStorage<Item>,
+    equippeds: &WriteStorage<Equipped>,
+) -> Option<Stow> {
+    if equipped_in(player_entity, item.slot, entities, carried_bys, equippeds).is_none() {
+        Some(Stow::Equip(item.slot))
+    } else if inventory_count(player_entity, carried_bys, items, equippeds) < INVENTORY_SIZE {
+        Some(Stow::Inventory)
+    } else {
+        None
+    }
+}
+
+/// Hand an item to a crab, either equipping it or putting it away in the inventory
+pub fn stow_item(
+    item_entity: Entity,
+    player_entity: Entity,
+    stow: Stow,
+    carried_bys: &mut WriteStorage<CarriedBy>,
+    renders: &mut WriteStorage<Renderable>,
+    equippeds: &mut WriteStorage<Equipped>,
+) {
+    carried_bys
+        .insert(
+            item_entity,
+            CarriedBy {
+                owner: player_entity,
+            },
+        )
+        .expect("Failed to give player item");
+    match stow {
+        Stow::Equip(slot) => {
+            equippeds
+                .insert(item_entity, Equipped { slot })
+                .expect("Failed to equip item");
+            // Bump up render order so item appears on top
+            renders
+                .insert(item_entity, Renderable { render_order: 0 })
+                .expect("Failed to increase render order");
+        }
+        Stow::Inventory => {
+            // Nobody can see what's in a crab's inventory
+            equippeds.remove(item_entity);
+            renders.remove(item_entity);
+        }
+    }
+}
+
+/// Pick an item up off the ground
+pub fn pick_up_item(
+    item_entity: Entity,
+    player_entity: Entity,
+    stow: Stow,
+    carried_bys: &mut WriteStorage<CarriedBy>,
+    pickups: &mut WriteStorage<WantsToBePickedUp>,
+    renders: &mut WriteStorage<Renderable>,
+    equippeds: &mut WriteStorage<Equipped>,
+) {
+    stow_item(
+        item_entity,
+        player_entity,
+        stow,
+        carried_bys,
+        renders,
+        equippeds,
+    );
+    pickups.remove(item_entity);
+}
+
+/// Put a carried item back on the ground wherever it is now
+pub fn drop_item(
+    item_entity: Entity,
+    item: &Item,
+    carried_bys: &mut WriteStorage<CarriedBy>,
+    pickups: &mut WriteStorage<WantsToBePickedUp>,
+    renders: &mut WriteStorage<Renderable>,
+    equippeds: &mut WriteStorage<Equipped>,
+) {
+    carried_bys.remove(item_entity);
+    equippeds.remove(item_entity);
+    pickups
+        .insert(
+            item_entity,
+            WantsToBePickedUp {
+                delay_ticks: DROP_PICKUP_DELAY_TICKS,
+            },
+        )
+        .expect("Failed to drop item");
+    renders
+        .insert(
+            item_entity,
+            Renderable {
+                render_order: item.ground_render_order,
+            },
+        )
+        .expect("Failed to restore render order");
+}
+
 pub struct PickUpSystem {}
 
 impl<'a> System<'a> for PickUpSystem {
     type SystemData = (
         Entities<'a>,
+        Read<'a, SpatialIndex>,
         ReadStorage<'a, PlayerInfo>,
+        ReadStorage<'a, Dead>,
+        ReadStorage<'a, AutoPickup>,
         ReadStorage<'a, Location>,
+        ReadStorage<'a, Item>,
         WriteStorage<'a, WantsToBePickedUp>,
         WriteStorage<'a, CarriedBy>,
         WriteStorage<'a, Renderable>,
+        WriteStorage<'a, Equipped>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (entities, player_infos, locations, mut pickups, mut carried_bys, mut renders) = data;
+        let (
+            entities,
+            spatial_index,
+            player_infos,
+            deads,
+            auto_pickups,
+            locations,
+            items,
+            mut pickups,
+            mut carried_bys,
+            mut renders,
+            mut equippeds,
+        ) = data;
 
         // Build list of new CarriedBy pairs so we don't mutate while we have immutable reference
-        let mut pick_up_pairs = Vec::new();
-        for (item_entity, item_location, _) in (&entities, &locations, &pickups).join() {
-            for (player_entity, player_location, _) in (&entities, &locations, &player_infos).join()
-            {
-                if within_pickup_distance(item_location, player_location) {
-                    pick_up_pairs.push((item_entity, player_entity));
-                }
+        let mut pick_up_pairs: Vec<(Entity, Entity, EquipmentSlot)> = Vec::new();
+        for (item_entity, item_location, item, pickup) in
+            (&entities, &locations, &items, &mut pickups).join()
+        {
+            if pickup.delay_ticks > 0 {
+                pickup.delay_ticks -= 1;
+                continue;
+            }
+            // Dead crabs can't pick things up, and neither can crabs that have turned
+            // auto-pickup off. Walking past something only picks it up if the slot it
+            // goes in is free, so crabs don't fill up their inventory by accident. If a
+            // few crabs are close enough, the same one always wins so the simulation is
+            // reproducible.
+            let picked_up_by = spatial_index
+                .query_radius(item_location, PICKUP_DISTANCE)
+                .into_iter()
+                .filter(|entity| {
+                    player_infos.get(*entity).is_some()
+                        && auto_pickups.get(*entity).is_some()
+                        && deads.get(*entity).is_none()
+                        && equipped_in(*entity, item.slot, &entities, &carried_bys, &equippeds)
+                            .is_none()
+                        && !pick_up_pairs
+                            .iter()
+                            .any(|(_, player, slot)| player == entity && *slot == item.slot)
+                })
+                .min_by_key(|entity| entity.id());
+            if let Some(player_entity) = picked_up_by {
+                pick_up_pairs.push((item_entity, player_entity, item.slot));
             }
         }
 
-        for (item_entity, player_entity) in pick_up_pairs {
-            // Insert a CarriedBy component for the item
-            carried_bys
-                .insert(
-                    item_entity,
-                    CarriedBy {
-                        owner: player_entity,
-                    },
-                )
-                .expect("Failed to give player item");
-            // Remove the WantsToBePickedUp component for the item
-            pickups.remove(item_entity);
-            // Bump up render order so item appears on top
-            renders
-                .insert(item_entity, Renderable { render_order: 0 })
-                .expect("Failed to increase render order");
+        for (item_entity, player_entity, slot) in pick_up_pairs {
+            pick_up_item(
+                item_entity,
+                player_entity,
+                Stow::Equip(slot),
+                &mut carried_bys,
+                &mut pickups,
+                &mut renders,
+                &mut equippeds,
+            );
         }
     }
 }
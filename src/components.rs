@@ -4,6 +4,8 @@ use specs::prelude::*;
 use specs::saveload::{ConvertSaveload, Marker};
 use specs_derive::*;
 
+use crate::map::TileType;
+
 // Helper for serializing entities in serde
 pub struct EntityMarker;
 
@@ -14,7 +16,7 @@ pub struct FPSTracker {
     pub prev_fps: u16,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, ConvertSaveload, Clone, PartialEq, Debug)]
 pub struct Location {
     pub x: i32,
     pub y: i32,
@@ -25,12 +27,12 @@ pub struct PlayerInfo {
     pub id: String,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, Clone, Deserialize, Serialize)]
 pub struct Renderable {
     pub render_order: i32,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, Clone, Deserialize, Serialize)]
 pub struct TextRenderable {
     pub text: String,
     pub font_size: f64,
@@ -38,21 +40,21 @@ pub struct TextRenderable {
     pub offset_y: f64,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, Clone, Deserialize, Serialize)]
 pub struct ChatRenderable {
     pub text: String,
     pub offset_x: f64,
     pub offset_y: f64,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, Clone, Deserialize, Serialize)]
 pub struct GraphicRenderable {
     pub image_name: String,
     pub offset_x: f64,
     pub offset_y: f64,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, Clone, Deserialize, Serialize)]
 pub struct GraphicAnimatable {
     pub image_names: Vec<String>,
     pub tick_interval: i16,
@@ -66,7 +68,7 @@ pub struct WantsToMoveTo {
     pub speed: i16,
 }
 
-#[derive(Component, ConvertSaveload, Clone)]
+#[derive(Component, Clone, Deserialize, Serialize)]
 pub struct Disappearing {
     pub total_ticks: u32,
     pub ticks_left: u32,
@@ -95,7 +97,82 @@ pub struct CrabAI {
 }
 
 #[derive(Component, Clone, Deserialize, Serialize)]
-pub struct WantsToBePickedUp {}
+pub struct WantsToBePickedUp {
+    /// Ticks until it can be picked up, so dropped items stay dropped for a moment
+    pub delay_ticks: u32,
+}
+
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct WantsToStab {
+    pub damage: i32,
+    /// Ticks to wait between stabs
+    pub cooldown_ticks: u32,
+    pub ticks_until_ready: u32,
+}
+
+/// Nothing else can walk onto the same tile as this entity
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct BlocksTile {}
+
+/// Tiles to walk through to reach `destination`, in order. Worked out from WantsToMoveTo.
+#[derive(Component, Clone)]
+pub struct Path {
+    pub steps: Vec<Location>,
+    pub destination: Location,
+    /// Saved up movement for tiles which cost more than we can move in one tick
+    pub movement_points: u32,
+}
+
+/// The type of tile a crab is standing on, so clients can show splashes and such
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct StandingOn {
+    pub tile_type: TileType,
+}
+
+/// Crab grabs any item it walks near without being asked
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct AutoPickup {}
+
+/// Places on a crab an item can be worn or held. Each can only hold one item at a time.
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Deserialize, Serialize)]
+pub enum EquipmentSlot {
+    Head,
+    Face,
+    Hand,
+}
+
+/// Anything a crab can carry around
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct Item {
+    /// Name of the prefab the item was made from
+    #[serde(default)]
+    pub kind: String,
+    /// Render order to go back to when the item is put down
+    #[serde(default)]
+    pub ground_render_order: i32,
+    /// Where the item goes when it's equipped
+    pub slot: EquipmentSlot,
+}
+
+/// Carried item that's being worn or held rather than stowed in the carrier's inventory.
+/// Items in the inventory aren't drawn and don't do anything until they're equipped.
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct Equipped {
+    pub slot: EquipmentSlot,
+}
+
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct Health {
+    pub current: i32,
+    pub max: i32,
+}
+
+/// Crab has been killed and is waiting to respawn
+#[derive(Component, Clone, Deserialize, Serialize)]
+pub struct Dead {
+    pub respawn_ticks: u32,
+}
 
+/// Player has lost their connection, and their crab is waiting for them to come back
 #[derive(Component, Clone, Deserialize, Serialize)]
-pub struct WantsToStab {}
+pub struct Idle {}
@@ -1,5 +1,6 @@
-use crate::components::{CrabAI, CrabAIState, Location, WantsToMoveTo};
+use crate::components::{CrabAI, CrabAIState, Dead, Location, WantsToMoveTo};
 use crate::map::{valid_walking_location, Map};
+use crate::occupancy::Occupancy;
 use specs::prelude::*;
 
 pub struct CrabAISystem {}
@@ -8,15 +9,19 @@ impl<'a> System<'a> for CrabAISystem {
     type SystemData = (
         Entities<'a>,
         ReadExpect<'a, Map>,
+        Read<'a, Occupancy>,
         WriteStorage<'a, CrabAI>,
+        ReadStorage<'a, Dead>,
         ReadStorage<'a, Location>,
         WriteStorage<'a, WantsToMoveTo>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (entities, map, mut crab_ais, locations, mut move_tos) = data;
+        let (entities, map, occupancy, mut crab_ais, deads, locations, mut move_tos) = data;
 
-        for (entity, mut crab_ai, location) in (&entities, &mut crab_ais, &locations).join() {
+        // Dead crabs don't do much of anything
+        for (entity, crab_ai, location, _) in (&entities, &mut crab_ais, &locations, !&deads).join()
+        {
             if crab_ai.ticks == crab_ai.tick_interval {
                 crab_ai.ticks = 0;
                 crab_ai.crab_state = match crab_ai.crab_state {
@@ -49,6 +54,9 @@ impl<'a> System<'a> for CrabAISystem {
             if !valid_walking_location(&map, &desired_location) {
                 continue; // Should not walk off the map
             }
+            if occupancy.is_blocked_for(desired_location.x, desired_location.y, entity) {
+                continue; // Nap until whatever's in the way moves
+            }
             move_tos
                 .insert(entity, desired_location)
                 .expect("Unable to insert WantsToMoveTo");
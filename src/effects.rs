@@ -0,0 +1,55 @@
+use crate::components::CarriedBy;
+use crate::events::{GameEvent, GameEvents};
+use crate::prefabs::Prefabs;
+use specs::prelude::*;
+use specs::shrev::ReaderId;
+
+/// Spawns the purely visual entities (blood, clouds, waves) that go along with game events
+#[derive(Default)]
+pub struct EffectsSystem {
+    reader: Option<ReaderId<GameEvent>>,
+}
+
+impl<'a> System<'a> for EffectsSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Prefabs>,
+        Read<'a, GameEvents>,
+        Read<'a, LazyUpdate>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.reader = Some(world.fetch_mut::<GameEvents>().register_reader());
+    }
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, prefabs, events, lazy) = data;
+
+        let reader = self.reader.as_mut().expect("EffectsSystem wasn't set up");
+        for event in events.read(reader) {
+            match event {
+                GameEvent::PlayerJoined { player } | GameEvent::PlayerRespawned { player } => {
+                    // Players wash up on the beach
+                    prefabs
+                        .build("wave", lazy.create_entity(&entities))
+                        .with(CarriedBy { owner: *player })
+                        .build();
+                }
+                GameEvent::PlayerLeft { location } => {
+                    prefabs
+                        .build("mushroom_cloud", lazy.create_entity(&entities))
+                        .with(location.clone())
+                        .build();
+                }
+                GameEvent::PlayerKilled { location, .. } => {
+                    // Add a blood splatter to highlight what happened here
+                    prefabs
+                        .build("blood_splatter", lazy.create_entity(&entities))
+                        .with(location.clone())
+                        .build();
+                }
+            }
+        }
+    }
+}
@@ -1,13 +1,13 @@
 use crate::components::*;
+use crate::events::{emit_event, GameEvent};
 use crate::map::{get_random_location_of_tile, Map, TileType};
+use crate::prefabs::{spawn_prefab, spawn_prefab_carried_by, Prefabs};
+use crate::spawner::ItemSpawnConfig;
 use oorandom::Rand32;
 use specs::prelude::*;
-use specs::saveload::{MarkedBuilder, SimpleMarker};
 use specs::world::EntitiesRes;
 use std::ops::Range;
 
-const MAXTREES: i32 = 20;
-
 //
 // Util functions
 //
@@ -41,7 +41,7 @@ pub fn delete_player(
     }
 }
 
-pub fn delete_player_with_id(mut ecs: &mut World, player_id: &String) {
+pub fn delete_player_with_id(ecs: &mut World, player_id: &String) {
     let maybe_entity = get_player_with_id(&ecs, &player_id);
     if maybe_entity.is_none() {
         return;
@@ -59,7 +59,23 @@ pub fn delete_player_with_id(mut ecs: &mut World, player_id: &String) {
         player_entity,
     );
     if let Some(location) = drop_location {
-        create_mushroom_cloud(&mut ecs, location.clone());
+        emit_event(ecs, GameEvent::PlayerLeft { location });
+    }
+}
+
+/// Mark the player's crab as waiting for them to reconnect, or as back again
+pub fn set_player_idle(ecs: &mut World, player_id: &String, idle: bool) {
+    let player_entity = match get_player_with_id(ecs, player_id) {
+        Some(entity) => entity,
+        None => return,
+    };
+    let mut idles = ecs.write_storage::<Idle>();
+    if idle {
+        idles
+            .insert(player_entity, Idle {})
+            .expect("Unable to insert Idle");
+    } else {
+        idles.remove(player_entity);
     }
 }
 
@@ -95,7 +111,7 @@ pub fn add_fps_tracker(ecs: &mut World, tracker: &FPSTracker) {
             offset_x: 0_f64,
             offset_y: 0_f64,
         })
-        .marked::<SimpleMarker<EntityMarker>>()
+        // Not marked because the tracker is local to each client and never networked
         .build();
 }
 
@@ -103,8 +119,16 @@ pub fn add_fps_tracker(ecs: &mut World, tracker: &FPSTracker) {
 // Crab entities
 //
 
-pub fn spawn_crab(mut ecs: &mut World, id: &str, name: &str, ai: bool) {
-    if get_player_with_id(&ecs, &id.into()).is_some() {
+/// AI crabs live in the grass, players wash up on the beach. None if the map has neither.
+pub fn crab_spawn_location(map: &Map, rng: &mut Rand32, ai: bool) -> Option<Location> {
+    match ai {
+        true => get_random_location_of_tile(map, rng, Some(TileType::Grass)),
+        false => get_random_location_of_tile(map, rng, Some(TileType::Sand)),
+    }
+}
+
+pub fn spawn_crab(ecs: &mut World, id: &str, name: &str, ai: bool) {
+    if get_player_with_id(ecs, &id.into()).is_some() {
         return; // Crab with this name already exists!
     }
 
@@ -115,9 +139,9 @@ pub fn spawn_crab(mut ecs: &mut World, id: &str, name: &str, ai: bool) {
     {
         let map = ecs.read_resource::<Map>();
         let mut rng = ecs.write_resource::<Rand32>();
-        location = match ai {
-            true => get_random_location_of_tile(&map, &mut rng, Some(TileType::Grass)),
-            false => get_random_location_of_tile(&map, &mut rng, Some(TileType::Sand)),
+        location = match crab_spawn_location(&map, &mut rng, ai) {
+            Some(location) => location,
+            None => return, // Nowhere on the map for it to go
         };
         if ai {
             let crab_state = if rng.rand_float() > 0.5 {
@@ -136,73 +160,31 @@ pub fn spawn_crab(mut ecs: &mut World, id: &str, name: &str, ai: bool) {
     }
 
     // Spawn the crab entity
-    let mut crab = ecs
-        .create_entity()
-        .with(location)
-        .with(PlayerInfo { id: id.into() })
-        .with(Renderable { render_order: 2 })
-        .with(TextRenderable {
-            text: name.into(),
-            font_size: 20_f64,
-            offset_x: 0_f64,
-            offset_y: 4_f64,
-        })
-        .with(GraphicRenderable {
-            image_name: String::from("rustacean_right"),
-            offset_x: 0_f64,
-            offset_y: 0_f64,
-        })
-        .with(GraphicAnimatable {
-            image_names: vec![
-                String::from("rustacean_right"),
-                String::from("rustacean_left"),
-            ],
-            tick_interval: 2,
-            ticks: 0,
-        });
-
-    if let Some(crab_ai) = crab_ai {
-        crab = crab.with(crab_ai);
+    let entity;
+    {
+        let prefabs = ecs.fetch::<Prefabs>();
+        let mut crab = prefabs
+            .build("crab", ecs.create_entity_unchecked())
+            .with(location)
+            .with(PlayerInfo { id: id.into() });
+        if let Some(crab_ai) = crab_ai {
+            crab = crab.with(crab_ai);
+        }
+        entity = crab.build();
+    }
+    if let Some(text_renderable) = ecs.write_storage::<TextRenderable>().get_mut(entity) {
+        text_renderable.text = name.into();
     }
-
-    let entity = crab.marked::<SimpleMarker<EntityMarker>>().build();
 
     // Create drop shadow for entity
-    create_drop_shadow(&mut ecs, entity);
+    spawn_prefab_carried_by(ecs, "drop_shadow", entity);
 
     // It not AI, then player character, so spawn on beach
     if !ai {
-        create_wave_for_entity(&mut ecs, entity);
+        emit_event(ecs, GameEvent::PlayerJoined { player: entity });
     }
 }
 
-fn create_wave_for_entity(ecs: &mut World, for_entity: Entity) {
-    ecs.create_entity()
-        .with(CarriedBy { owner: for_entity })
-        .with(Renderable { render_order: 0 })
-        .with(GraphicRenderable {
-            image_name: String::from("water_wave_0"),
-            offset_x: 0_f64,
-            offset_y: 0_f64,
-        })
-        .with(GraphicAnimatable {
-            image_names: vec![
-                String::from("water_wave_0"),
-                String::from("water_wave_1"),
-                String::from("water_wave_2"),
-                String::from("water_wave_2"),
-            ],
-            tick_interval: 3,
-            ticks: 0,
-        })
-        .with(Disappearing {
-            total_ticks: 9,
-            ticks_left: 15,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
 pub fn create_chat_bubble(ecs: &mut World, text: String, for_entity: Entity) {
     // Delete any other chat bubbles this player has had up until now
     {
@@ -221,177 +203,37 @@ pub fn create_chat_bubble(ecs: &mut World, text: String, for_entity: Entity) {
                 .expect("Could not delete chat bubble");
         }
     }
-    ecs.create_entity()
-        .with(CarriedBy { owner: for_entity })
-        .with(Renderable { render_order: 0 })
-        .with(ChatRenderable {
-            text: text,
-            offset_x: -2_f64,
-            offset_y: -10_f64,
-        })
-        .with(Disappearing {
-            total_ticks: 100,
-            ticks_left: 100,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
+    let bubble = spawn_prefab_carried_by(ecs, "chat_bubble", for_entity);
+    if let Some(chat_renderable) = ecs.write_storage::<ChatRenderable>().get_mut(bubble) {
+        chat_renderable.text = text;
+    }
 }
 
 //
 // World entities
 //
 
-pub fn create_tree(ecs: &mut World, x: i32, y: i32) {
-    ecs.create_entity()
-        .with(Location { x, y })
-        .with(Renderable { render_order: 4 })
-        .with(TextRenderable {
-            text: String::from("🌴"),
-            font_size: 40_f64,
-            offset_x: -0.5_f64,
-            offset_y: 0_f64,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
 /// Fill the map with entities
-pub fn fill_map(ecs: &mut World, map: &Map, mut rng: &mut Rand32) {
-    for _ in 0..MAXTREES {
-        let location = get_random_location_of_tile(&map, &mut rng, Some(TileType::Grass));
-        create_tree(ecs, location.x, location.y);
-    }
-    create_knife(
-        ecs,
-        get_random_location_of_tile(&map, &mut rng, Some(TileType::Grass)),
+pub fn fill_map(ecs: &mut World, map: &Map, rng: &mut Rand32, trees: u32, items: &ItemSpawnConfig) {
+    let mut spawns = vec![("tree", trees)];
+    spawns.extend(
+        items
+            .targets
+            .iter()
+            .map(|(name, target)| (name.as_str(), *target)),
     );
-    create_hat(ecs, get_random_location_of_tile(&map, &mut rng, None));
-    create_glasses(ecs, get_random_location_of_tile(&map, &mut rng, None));
-}
-
-//
-// Effects
-//
-
-pub fn create_poop(ecs: &mut World, location: Location) {
-    ecs.create_entity()
-        .with(location)
-        .with(Renderable { render_order: 3 })
-        .with(TextRenderable {
-            text: String::from("💩"),
-            font_size: 20_f64,
-            offset_x: 0_f64,
-            offset_y: 3_f64,
-        })
-        .with(Disappearing {
-            total_ticks: 20,
-            ticks_left: 100,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
-pub fn create_blood_splatter(ecs: &mut World, location: Location) {
-    ecs.create_entity()
-        .with(location)
-        .with(Renderable { render_order: 5 })
-        .with(GraphicRenderable {
-            image_name: String::from("blood_splatter"),
-            offset_x: 0_f64,
-            offset_y: 0_f64,
-        })
-        .with(Disappearing {
-            total_ticks: 20,
-            ticks_left: 100,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
-pub fn create_mushroom_cloud(ecs: &mut World, location: Location) {
-    ecs.create_entity()
-        .with(location)
-        .with(Renderable { render_order: 0 })
-        .with(GraphicRenderable {
-            image_name: String::from("mushroom_cloud"),
-            offset_x: 0_f64,
-            offset_y: 0_f64,
-        })
-        .with(Disappearing {
-            total_ticks: 20,
-            ticks_left: 20,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
-pub fn create_drop_shadow(ecs: &mut World, for_entity: Entity) {
-    ecs.create_entity()
-        .with(CarriedBy { owner: for_entity })
-        .with(Renderable { render_order: 4 })
-        .with(GraphicRenderable {
-            image_name: String::from("drop_shadow"),
-            offset_x: 0_f64,
-            offset_y: 0_f64,
-        })
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
-//
-// Items
-//
-
-pub fn create_knife(ecs: &mut World, location: Location) {
-    ecs.create_entity()
-        .with(location)
-        .with(Renderable { render_order: 3 })
-        .with(TextRenderable {
-            text: String::from("🔪"),
-            font_size: 40_f64,
-            offset_x: 2.5_f64,
-            offset_y: 2_f64,
-        })
-        .with(Disappearing {
-            total_ticks: 20,
-            ticks_left: 200,
-        })
-        .with(WantsToBePickedUp {})
-        .with(WantsToStab {})
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
-//
-// Clothing
-//
-
-pub fn create_hat(ecs: &mut World, location: Location) {
-    ecs.create_entity()
-        .with(location)
-        .with(Renderable { render_order: 0 })
-        .with(TextRenderable {
-            text: String::from("🎩"),
-            font_size: 20_f64,
-            offset_x: 0_f64,
-            offset_y: -2_f64,
-        })
-        .with(WantsToBePickedUp {})
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
-}
-
-pub fn create_glasses(ecs: &mut World, location: Location) {
-    ecs.create_entity()
-        .with(location)
-        .with(Renderable { render_order: 0 })
-        .with(TextRenderable {
-            text: String::from("🕶"),
-            font_size: 20_f64,
-            offset_x: 0.2_f64,
-            offset_y: 1.9_f64,
-        })
-        .with(WantsToBePickedUp {})
-        .marked::<SimpleMarker<EntityMarker>>()
-        .build();
+    for (name, count) in spawns {
+        let spawn_tile = ecs
+            .fetch::<Prefabs>()
+            .get(name)
+            .and_then(|prefab| prefab.spawn_tile);
+        for _ in 0..count {
+            match get_random_location_of_tile(map, rng, spawn_tile) {
+                Some(location) => {
+                    spawn_prefab(ecs, name, location);
+                }
+                None => break, // The map doesn't have anywhere to put it
+            }
+        }
+    }
 }
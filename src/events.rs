@@ -0,0 +1,27 @@
+use crate::components::Location;
+use specs::prelude::*;
+use specs::shrev::EventChannel;
+
+/// Things that happened during a tick which other systems may want to react to
+#[derive(Clone)]
+pub enum GameEvent {
+    /// A player crab appeared in the world
+    PlayerJoined { player: Entity },
+    /// A player crab left the world on their own accord
+    PlayerLeft { location: Location },
+    /// `killer` stabbed `victim` to death at `location`
+    PlayerKilled {
+        victim: Entity,
+        killer: Entity,
+        location: Location,
+    },
+    /// A dead crab came back to life
+    PlayerRespawned { player: Entity },
+}
+
+pub type GameEvents = EventChannel<GameEvent>;
+
+/// Queue up an event from outside of a system
+pub fn emit_event(ecs: &World, event: GameEvent) {
+    ecs.write_resource::<GameEvents>().single_write(event);
+}
@@ -0,0 +1,65 @@
+use crate::components::{CrabAI, Dead, Health, Location, WantsToMoveTo};
+use crate::entities::crab_spawn_location;
+use crate::events::{GameEvent, GameEvents};
+use crate::map::Map;
+use oorandom::Rand32;
+use specs::prelude::*;
+
+/// Brings dead crabs back to life once they've waited long enough
+pub struct RespawnSystem {}
+
+impl<'a> System<'a> for RespawnSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Map>,
+        WriteExpect<'a, Rand32>,
+        ReadStorage<'a, CrabAI>,
+        WriteStorage<'a, Dead>,
+        WriteStorage<'a, Health>,
+        WriteStorage<'a, Location>,
+        WriteStorage<'a, WantsToMoveTo>,
+        Write<'a, GameEvents>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            map,
+            mut rng,
+            crab_ais,
+            mut deads,
+            mut healths,
+            mut locations,
+            mut move_tos,
+            mut events,
+        ) = data;
+
+        let mut respawns = Vec::new();
+        for (entity, dead) in (&entities, &mut deads).join() {
+            if dead.respawn_ticks > 0 {
+                dead.respawn_ticks -= 1;
+            } else {
+                respawns.push(entity);
+            }
+        }
+
+        for entity in respawns {
+            let ai = crab_ais.get(entity).is_some();
+            let location = match crab_spawn_location(&map, &mut rng, ai) {
+                Some(location) => location,
+                None => continue, // Nowhere to come back to, so stay dead and try again
+            };
+            deads.remove(entity);
+            move_tos.remove(entity);
+            if let Some(health) = healths.get_mut(entity) {
+                health.current = health.max;
+            }
+            locations
+                .insert(entity, location)
+                .expect("Failed to move respawned crab");
+            if !ai {
+                events.single_write(GameEvent::PlayerRespawned { player: entity });
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::components::*;
+use crate::spatial::SpatialIndex;
+use specs::prelude::*;
+use specs::saveload::{Marker, SimpleMarker};
+use std::collections::{BTreeSet, HashSet};
+
+/// Carried things can be carried by carried things, but never this deep
+const MAX_CARRY_DEPTH: usize = 8;
+
+/// Whatever is ultimately carrying `entity`, or `entity` itself if it isn't carried.
+/// Carried entities come and go together with their owner.
+fn anchor(entity: Entity, carried_bys: &ReadStorage<CarriedBy>) -> Entity {
+    let mut anchor = entity;
+    for _ in 0..MAX_CARRY_DEPTH {
+        match carried_bys.get(anchor) {
+            Some(carried_by) => anchor = carried_by.owner,
+            None => break,
+        }
+    }
+    anchor
+}
+
+/// Marker ids of every entity the player with `player_id` needs to know about: anything
+/// closer than `radius` tiles to their crab, including everything carried by it (such as
+/// chat bubbles), plus anything that isn't anywhere on the map. Players without a crab
+/// only get the latter.
+pub fn entities_of_interest(ecs: &World, player_id: &str, radius: f64) -> BTreeSet<u64> {
+    let entities = ecs.entities();
+    let markers = ecs.read_storage::<SimpleMarker<EntityMarker>>();
+    let locations = ecs.read_storage::<Location>();
+    let carried_bys = ecs.read_storage::<CarriedBy>();
+    let player_infos = ecs.read_storage::<PlayerInfo>();
+    let spatial_index = ecs.fetch::<SpatialIndex>();
+
+    let centre = (&player_infos, &locations)
+        .join()
+        .find(|(player_info, _)| player_info.id == player_id)
+        .map(|(_, location)| location.clone());
+    // The index was built during the last tick, so skip anything deleted since
+    let nearby: HashSet<Entity> = match &centre {
+        Some(centre) => spatial_index
+            .query_radius(centre, radius)
+            .into_iter()
+            .filter(|entity| entities.is_alive(*entity))
+            .collect(),
+        None => HashSet::new(),
+    };
+
+    let mut interest: BTreeSet<u64> = nearby
+        .iter()
+        .filter(|entity| carried_bys.get(**entity).is_none())
+        .filter_map(|entity| markers.get(*entity))
+        .map(|marker| marker.id())
+        .collect();
+    // Carried things are wherever their owner is, whatever their own Location says
+    interest.extend(
+        (&entities, &markers, &carried_bys)
+            .join()
+            .filter(|(entity, _, _)| {
+                let anchor = anchor(*entity, &carried_bys);
+                locations.get(anchor).is_none() || nearby.contains(&anchor)
+            })
+            .map(|(_, marker, _)| marker.id()),
+    );
+    // Anything that isn't on the map at all
+    interest.extend(
+        (&markers, !&locations, !&carried_bys)
+            .join()
+            .map(|(marker, _, _)| marker.id()),
+    );
+    interest
+}
@@ -0,0 +1,213 @@
+use crate::components::{EntityMarker, Location};
+use crate::map::euclidean_distance;
+use crate::saveload_system::AppliedSnapshot;
+use crate::scheduler::DEFAULT_TICK_INTERVAL;
+use specs::prelude::*;
+use specs::saveload::{Marker, SimpleMarker};
+use std::collections::{HashMap, VecDeque};
+
+/// How far behind the newest snapshot remote entities are drawn, unless configured otherwise.
+/// Two snapshots' worth at the default send rate, so one arriving late doesn't leave us with
+/// nothing newer to move towards.
+pub const DEFAULT_INTERPOLATION_DELAY_MS: f64 = 200.0;
+
+/// Entities which jump further than this between snapshots (respawning, say) are moved
+/// straight there rather than slid across the island
+const MAX_INTERPOLATED_DISTANCE: f64 = 3.0;
+
+/// Snapshots we keep at most, however long the delay is
+const MAX_BUFFERED_SNAPSHOTS: usize = 32;
+
+/// How much of the way our guess at the server's clock moves towards a snapshot that
+/// arrived later than we expected. Ones that arrive early are believed straight away.
+const CLOCK_SMOOTHING: f64 = 0.05;
+
+/// Where every marked entity was in a snapshot, and which tick it was taken on
+struct TickSnapshot {
+    tick: u64,
+    locations: HashMap<u64, Location>,
+}
+
+/// Recent snapshots from the server, so remote entities can be drawn gliding between tiles
+/// rather than hopping from one to the next whenever a snapshot arrives. Snapshots are
+/// placed by the tick they were taken on rather than when they arrived, so one that's
+/// held up on the way isn't drawn as if everything in it happened late.
+pub struct SnapshotBuffer {
+    delay: f64,
+    /// Milliseconds between the server's ticks
+    tick_interval: f64,
+    /// Our clock minus the server's, as best we can tell from when snapshots arrive
+    clock_offset: Option<f64>,
+    snapshots: VecDeque<TickSnapshot>,
+}
+
+impl SnapshotBuffer {
+    /// Buffer which draws entities `delay` milliseconds behind the newest snapshot
+    pub fn new(delay: f64) -> SnapshotBuffer {
+        SnapshotBuffer {
+            delay,
+            tick_interval: DEFAULT_TICK_INTERVAL.as_secs_f64() * 1000.0,
+            clock_offset: None,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Use the server's tick length, which it tells us when we connect
+    pub fn set_tick_interval(&mut self, tick_interval: f64) {
+        if tick_interval != self.tick_interval {
+            self.tick_interval = tick_interval;
+            self.clock_offset = None;
+            self.snapshots.clear();
+        }
+    }
+
+    /// When `tick` happened on the server's clock, in milliseconds
+    fn tick_time(&self, tick: u64) -> f64 {
+        tick as f64 * self.tick_interval
+    }
+
+    /// Where to draw the entity with marker id `id` at `time` on our clock, or None if it
+    /// wasn't in the snapshots we have
+    pub fn position(&self, id: u64, time: f64) -> Option<(f64, f64)> {
+        let render_time = time - self.clock_offset? - self.delay;
+        // The newest snapshot from before the render time, and the one after it if any
+        let after = self
+            .snapshots
+            .iter()
+            .position(|snapshot| self.tick_time(snapshot.tick) > render_time);
+        let (from, to) = match after {
+            Some(0) => (None, self.snapshots.front()),
+            Some(index) => (self.snapshots.get(index - 1), self.snapshots.get(index)),
+            None => (self.snapshots.back(), None),
+        };
+        let located = |snapshot: &TickSnapshot| {
+            snapshot
+                .locations
+                .get(&id)
+                .map(|location| (self.tick_time(snapshot.tick), location.clone()))
+        };
+        match (from.and_then(located), to.and_then(located)) {
+            (Some((from_time, from)), Some((to_time, to)))
+                if euclidean_distance(&from, &to) <= MAX_INTERPOLATED_DISTANCE =>
+            {
+                let t = (render_time - from_time) / (to_time - from_time);
+                Some((
+                    from.x as f64 + (to.x - from.x) as f64 * t,
+                    from.y as f64 + (to.y - from.y) as f64 * t,
+                ))
+            }
+            // Too far apart to blend, or only in the one snapshot, so it's wherever it was
+            // last seen by the render time, if we've caught up to it at all
+            (Some((_, location)), _) => Some((location.x as f64, location.y as f64)),
+            (None, Some((_, location))) => Some((location.x as f64, location.y as f64)),
+            (None, None) => None,
+        }
+    }
+
+    /// Add the snapshot taken on `snapshot.tick`, which arrived at `time` on our clock
+    fn push(&mut self, snapshot: TickSnapshot, time: f64) {
+        if let Some(newest) = self.snapshots.back() {
+            if newest.tick >= snapshot.tick {
+                // The server has started over, so nothing we have lines up with it
+                self.snapshots.clear();
+                self.clock_offset = None;
+            }
+        }
+        let offset = time - self.tick_time(snapshot.tick);
+        let offset = match self.clock_offset {
+            Some(current) if offset > current => current + (offset - current) * CLOCK_SMOOTHING,
+            _ => offset,
+        };
+        self.clock_offset = Some(offset);
+
+        let render_time = time - offset - self.delay;
+        self.snapshots.push_back(snapshot);
+        // Anything before the latest snapshot older than the render time won't be drawn again
+        while self.snapshots.len() > MAX_BUFFERED_SNAPSHOTS
+            || (self.snapshots.len() > 2 && self.tick_time(self.snapshots[1].tick) <= render_time)
+        {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+/// Remember where every marked entity is in the snapshot we just applied, which arrived at
+/// `time` in milliseconds
+pub fn buffer_snapshot(ecs: &World, time: f64) {
+    let tick = match ecs.fetch::<AppliedSnapshot>().tick {
+        Some(tick) => tick,
+        None => return,
+    };
+    let markers = ecs.read_storage::<SimpleMarker<EntityMarker>>();
+    let locations = ecs.read_storage::<Location>();
+    let locations = (&markers, &locations)
+        .join()
+        .map(|(marker, location)| (marker.id(), location.clone()))
+        .collect();
+    ecs.fetch_mut::<SnapshotBuffer>()
+        .push(TickSnapshot { tick, locations }, time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Snapshot with a single entity, marker id 1, at (x, 0)
+    fn snapshot(tick: u64, x: i32) -> TickSnapshot {
+        let mut locations = HashMap::new();
+        locations.insert(1, Location { x, y: 0 });
+        TickSnapshot { tick, locations }
+    }
+
+    /// A buffer drawing 200ms behind a server ticking every 100ms
+    fn new_buffer() -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::new(200.0);
+        buffer.set_tick_interval(100.0);
+        buffer
+    }
+
+    fn assert_drawn_at(buffer: &SnapshotBuffer, time: f64, x: f64) {
+        let (drawn_x, drawn_y) = buffer.position(1, time).expect("Entity isn't drawn");
+        assert!((drawn_x - x).abs() < 1e-9, "drawn at {} not {}", drawn_x, x);
+        assert_eq!(drawn_y, 0.0);
+    }
+
+    #[test]
+    fn entities_are_drawn_between_the_snapshots_either_side() {
+        let mut buffer = new_buffer();
+        // Every snapshot takes 50ms to reach us
+        buffer.push(snapshot(10, 0), 1_050.0);
+        buffer.push(snapshot(11, 2), 1_150.0);
+        buffer.push(snapshot(12, 4), 1_250.0);
+
+        // Half way between ticks 10 and 11, then 11 and 12
+        assert_drawn_at(&buffer, 1_300.0, 1.0);
+        assert_drawn_at(&buffer, 1_400.0, 3.0);
+        // Before the first snapshot and after the last we can only show where it was
+        assert_drawn_at(&buffer, 1_000.0, 0.0);
+        assert_drawn_at(&buffer, 2_000.0, 4.0);
+        assert_eq!(buffer.position(2, 1_300.0), None);
+    }
+
+    #[test]
+    fn late_snapshots_are_drawn_when_they_were_taken() {
+        let mut buffer = new_buffer();
+        buffer.push(snapshot(10, 0), 1_050.0);
+        // Held up for an extra 100ms, which only nudges our idea of the server's clock
+        buffer.push(snapshot(11, 2), 1_250.0);
+        assert_drawn_at(&buffer, 1_305.0, 1.0);
+
+        // One that's quicker than ever sets the clock straight away
+        buffer.push(snapshot(12, 4), 1_230.0);
+        assert_drawn_at(&buffer, 1_380.0, 3.0);
+    }
+
+    #[test]
+    fn entities_which_jump_are_not_slid_across_the_map() {
+        let mut buffer = new_buffer();
+        buffer.push(snapshot(10, 0), 1_050.0);
+        buffer.push(snapshot(11, 20), 1_150.0);
+        assert_drawn_at(&buffer, 1_300.0, 0.0);
+        assert_drawn_at(&buffer, 1_350.0, 20.0);
+    }
+}
@@ -4,10 +4,22 @@ pub mod animation;
 pub mod carry;
 pub mod components;
 pub mod crab_ai;
+pub mod effects;
 pub mod entities;
+pub mod events;
+pub mod health;
+pub mod interest;
+pub mod interpolation;
 pub mod map;
 pub mod movement;
+pub mod occupancy;
+pub mod pathfinding;
+pub mod prediction;
+pub mod prefabs;
 pub mod saveload_system;
+pub mod scheduler;
+pub mod spatial;
+pub mod spawner;
 pub mod state;
 pub mod string_writer;
 pub mod weapons;
@@ -6,35 +6,96 @@ use specs::prelude::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 use stdweb::traits::*;
+use stdweb::unstable::TryInto;
+use stdweb::web::html_element::InputElement;
 use stdweb::web::{event::ClickEvent, event::KeyDownEvent, Date, IEventTarget};
 
 mod canvas;
+mod network;
 use canvas::{Canvas, DrawSystem};
 use ferris_chat::components::*;
 use ferris_chat::entities::*;
-use ferris_chat::saveload_system::{load_game, serialize_player_input, PlayerInput};
-use ferris_chat::state::{handle_chat_input, handle_click, handle_input, initialize_ecs, State};
+use ferris_chat::interpolation::{buffer_snapshot, SnapshotBuffer, DEFAULT_INTERPOLATION_DELAY_MS};
+use ferris_chat::prediction::{load_game_predicted, predict_click, predict_tick, Prediction};
+use ferris_chat::prefabs::Prefabs;
+use ferris_chat::saveload_system::{
+    AppliedSnapshot, PlayerInput, ServerMessage, COMPONENT_REGISTRY_VERSION,
+};
+use ferris_chat::state::{
+    auto_pickup_enabled, handle_change_name, handle_chat_input, handle_drop, handle_give,
+    handle_input, handle_pick_up, handle_set_auto_pickup, handle_swap, handle_unequip,
+    initialize_ecs, State, WorldConfig,
+};
+use network::{ClientEvent, Connection, EventQueue};
 
 pub struct GUIComponents {
     pub fps_tracker: FPSTracker,
 }
 
-fn handle_client_input(mut ecs: &mut World, input: &str) {
-    match input {
-        "p" => {}
-        _ => return,
-    }
+/// The name we asked for, used whenever our crab needs creating
+struct PlayerName(String);
 
+/// Once we've applied a snapshot from the server, the server owns the world and we
+/// shouldn't create entities of our own.
+fn is_remote_session(ecs: &World) -> bool {
+    ecs.fetch::<AppliedSnapshot>().tick.is_some()
+}
+
+/// Send an input to the server, if we're connected to one
+fn send_input(ecs: &World, player_input: PlayerInput) {
+    ecs.fetch::<Connection>().send(player_input);
+}
+
+fn handle_client_input(mut ecs: &mut World, input: &str) {
     let player_id = ecs.fetch::<String>().to_string();
-    let player_input = PlayerInput::SpecialInput {
-        id: player_id.clone(),
-        input: input.into(),
+    let player_input = match input {
+        "p" => PlayerInput::SpecialInput {
+            input: input.into(),
+        },
+        "e" => PlayerInput::PickUp,
+        "q" => PlayerInput::Drop,
+        "g" => PlayerInput::Give,
+        "t" => PlayerInput::SetAutoPickup {
+            enabled: !auto_pickup_enabled(&ecs, &player_id),
+        },
+        // Number keys cycle through what's in each slot, and shift+number takes it off
+        "1" => PlayerInput::Swap {
+            slot: EquipmentSlot::Head,
+        },
+        "2" => PlayerInput::Swap {
+            slot: EquipmentSlot::Face,
+        },
+        "3" => PlayerInput::Swap {
+            slot: EquipmentSlot::Hand,
+        },
+        "!" => PlayerInput::Unequip {
+            slot: EquipmentSlot::Head,
+        },
+        "@" => PlayerInput::Unequip {
+            slot: EquipmentSlot::Face,
+        },
+        "#" => PlayerInput::Unequip {
+            slot: EquipmentSlot::Hand,
+        },
+        _ => return,
     };
-    stdweb::web::window()
-        .local_storage()
-        .insert("player_input", &serialize_player_input(player_input))
-        .expect("Failed to write player_input to local_storage");
-    handle_input(&mut ecs, input, &player_id);
+
+    send_input(&ecs, player_input.clone());
+    if is_remote_session(&ecs) {
+        return;
+    }
+    match player_input {
+        PlayerInput::SpecialInput { input } => handle_input(&mut ecs, &input, &player_id),
+        PlayerInput::PickUp => handle_pick_up(&mut ecs, &player_id),
+        PlayerInput::Drop => handle_drop(&mut ecs, &player_id),
+        PlayerInput::Give => handle_give(&mut ecs, &player_id),
+        PlayerInput::Swap { slot } => handle_swap(&mut ecs, slot, &player_id),
+        PlayerInput::Unequip { slot } => handle_unequip(&mut ecs, slot, &player_id),
+        PlayerInput::SetAutoPickup { enabled } => {
+            handle_set_auto_pickup(&mut ecs, enabled, &player_id)
+        }
+        _ => {}
+    }
 }
 
 fn handle_client_click(mut ecs: &mut World, screen_x: i32, screen_y: i32) {
@@ -58,16 +119,9 @@ fn handle_client_click(mut ecs: &mut World, screen_x: i32, screen_y: i32) {
         x = iso_coordinates.0 as i32;
         y = iso_coordinates.1 as i32;
     }
-    let player_input = PlayerInput::Click {
-        id: player_id.clone(),
-        x,
-        y,
-    };
-    stdweb::web::window()
-        .local_storage()
-        .insert("player_input", &serialize_player_input(player_input))
-        .expect("Failed to write player_input to local_storage");
-    handle_click(&mut ecs, x, y, &player_id);
+    // Start walking now rather than waiting to hear back from the server
+    let sequence = predict_click(&mut ecs, x, y, &player_id);
+    send_input(&ecs, PlayerInput::Click { x, y, sequence });
 }
 
 /// System for tracking FPS. In main file because depends on stdweb.
@@ -85,25 +139,33 @@ fn update_fps_tracker(mut ecs: &mut World, fps_tracker: &mut FPSTracker) {
     add_fps_tracker(&mut ecs, &fps_tracker);
 }
 
-fn read_from_local_storage(mut ecs: &mut World) {
-    // Check for chat_input
-    let chat_input = stdweb::web::window().local_storage().get("chat_input");
-    if let Some(chat_msg) = chat_input {
-        if chat_msg.len() == 0 {
-            return; // Don't render a bubble if nothing was said
-        }
-        let player_id = ecs.fetch::<String>().to_string();
-        let player_input = PlayerInput::Chat {
-            id: player_id.clone(),
-            message: chat_msg.clone(),
-        };
-        stdweb::web::window()
-            .local_storage()
-            .insert("player_input", &serialize_player_input(player_input))
-            .expect("Failed to write player_input to local_storage");
-        handle_chat_input(&mut ecs, &chat_msg, &player_id);
+fn handle_rename(mut ecs: &mut World, name: String) {
+    if name.is_empty() {
+        return;
+    }
+    let player_id = ecs.fetch::<String>().to_string();
+    send_input(&ecs, PlayerInput::ChangeName { name: name.clone() });
+    if !is_remote_session(&ecs) {
+        handle_change_name(&mut ecs, &name, &player_id);
+    }
+    // Remember the name for next time we need to create our crab
+    ecs.fetch_mut::<PlayerName>().0 = name;
+}
+
+fn handle_chat(mut ecs: &mut World, message: String) {
+    if message.is_empty() {
+        return; // Don't render a bubble if nothing was said
+    }
+    let player_id = ecs.fetch::<String>().to_string();
+    send_input(
+        &ecs,
+        PlayerInput::Chat {
+            message: message.clone(),
+        },
+    );
+    if !is_remote_session(&ecs) {
+        handle_chat_input(&mut ecs, &message, &player_id);
     }
-    stdweb::web::window().local_storage().remove("chat_input");
 }
 
 fn create_player(mut ecs: &mut World) {
@@ -111,61 +173,170 @@ fn create_player(mut ecs: &mut World) {
     if get_player_with_id(&ecs, &player_id).is_some() {
         return; // Player is alive and healthy
     }
-    let player_name = match stdweb::web::window().local_storage().get("player_name") {
-        Some(name) => name.to_string(),
-        None => String::from(""),
-    };
-    let player_input = PlayerInput::CreatePlayer {
-        id: player_id.clone(),
-        name: player_name.clone(),
-    };
-    stdweb::web::window()
-        .local_storage()
-        .insert("player_input", &serialize_player_input(player_input))
-        .expect("Failed to write player_input to local_storage");
-    spawn_crab(&mut ecs, &player_id, &player_name, false);
-}
-
-fn rendering_tick(state: &mut State, gui: &mut GUIComponents) {
-    let remote_session_save_state = stdweb::web::window().local_storage().get("save_state");
-    let mut remote_session = false;
-    if let Some(save_state) = remote_session_save_state {
-        if save_state.len() > 0 {
-            remote_session = true;
-            // If save state exists in local storage, then we're connected to a remote session.
-            // Load that into our ECS instead of running systems manually.
-            load_game(&mut state.ecs, save_state);
-            stdweb::web::window().local_storage().remove("save_state");
+    let player_name = ecs.fetch::<PlayerName>().0.clone();
+    send_input(
+        &ecs,
+        PlayerInput::CreatePlayer {
+            name: player_name.clone(),
+        },
+    );
+    if !is_remote_session(&ecs) {
+        spawn_crab(&mut ecs, &player_id, &player_name, false);
+    }
+}
+
+/// Let the server know which snapshot we're showing so it can send us deltas from there
+fn acknowledge_snapshot(ecs: &World) {
+    if let Some(tick) = ecs.fetch::<AppliedSnapshot>().tick {
+        send_input(ecs, PlayerInput::AckSnapshot { tick });
+    }
+}
+
+fn handle_server_message(ecs: &mut World, message: ServerMessage) {
+    match message {
+        ServerMessage::Welcome {
+            component_registry_version,
+            ..
+        } if component_registry_version != COMPONENT_REGISTRY_VERSION => {
+            // We'd misread every snapshot, so there's no point staying connected
+            ecs.fetch_mut::<Connection>().give_up(String::from(
+                "the server is running a different version, reload the page",
+            ));
+        }
+        ServerMessage::Welcome {
+            player_id,
+            resume_token,
+            tick_interval_ms,
+            ..
+        } => {
+            // The server decides who we are, so swap out our local id for theirs
+            *ecs.write_resource::<String>() = player_id;
+            ecs.fetch_mut::<Connection>().set_resume_token(resume_token);
+            ecs.fetch_mut::<SnapshotBuffer>()
+                .set_tick_interval(tick_interval_ms);
+        }
+        ServerMessage::SaveState(save_state) => {
+            // The server's map needn't be the size of the one we made to play locally
+            if let Some(map) = save_state.map() {
+                ecs.fetch_mut::<Canvas>()
+                    .fit_map(map.width as u32, map.height as u32);
+            }
+            // If the delta was based on a snapshot we don't have, our ack will get the
+            // server back in sync.
+            let player_id = ecs.fetch::<String>().to_string();
+            match load_game_predicted(ecs, save_state, &player_id) {
+                Ok(true) => buffer_snapshot(ecs, Date::now()),
+                Ok(false) => {}
+                Err(error) => console!(error, format!("Bad snapshot from the server: {}", error)),
+            }
+            acknowledge_snapshot(ecs);
+        }
+        ServerMessage::ProtocolError { message } => {
+            console!(error, format!("Server rejected our input: {}", message));
+        }
+        ServerMessage::PlayerKilled { killer, victim } => {
+            let text = format!("{} stabbed {}!", killer, victim);
+            js! {
+                document.getElementById("kill_feed").textContent = @{text};
+            }
+        }
+    }
+}
+
+fn handle_client_event(mut ecs: &mut World, event: ClientEvent) {
+    match event {
+        ClientEvent::Connected => ecs.fetch_mut::<Connection>().on_connected(),
+        ClientEvent::Disconnected { reason } => {
+            ecs.fetch_mut::<Connection>().on_disconnected(reason);
+            // Clicks from before are lost with the connection, and so is their numbering
+            *ecs.write_resource::<Prediction>() = Prediction::default();
         }
+        ClientEvent::Reconnect => ecs.fetch_mut::<Connection>().reconnect(),
+        ClientEvent::Message(message) => handle_server_message(&mut ecs, message),
+        ClientEvent::Chat { message } => handle_chat(&mut ecs, message),
+        ClientEvent::Rename { name } => handle_rename(&mut ecs, name),
+    }
+}
+
+fn rendering_tick(state: &mut State) {
+    // Catch up on everything the socket and the page did since last tick
+    let events = state.ecs.fetch::<EventQueue>().drain();
+    for event in events {
+        handle_client_event(&mut state.ecs, event);
     }
-    if !remote_session {
+    if !is_remote_session(&state.ecs) {
         // If no remote sesson save state, then run our ECS locally.
         state.tick();
+    } else {
+        // Otherwise the server runs everything but our own crab's footsteps
+        let player_id = state.ecs.fetch::<String>().to_string();
+        predict_tick(&mut state.ecs, &player_id);
     }
 
-    // Check the window local storage for updates
-    read_from_local_storage(&mut state.ecs);
+    // Check if our character is alive. If not, create them
+    create_player(&mut state.ecs);
+}
 
+/// Draw as often as the browser lets us, so remote crabs glide between snapshots
+fn draw_frame(state: &mut State, gui: &mut GUIComponents) {
     // Update the FPS GUI
     update_fps_tracker(&mut state.ecs, &mut gui.fps_tracker);
+    // Clear out the tracker it replaced
+    state.ecs.maintain();
 
-    // Invoke the draw system last
-    let mut draw_system = DrawSystem {};
+    let mut draw_system = DrawSystem { now: Date::now() };
     draw_system.run_now(&state.ecs);
+}
 
-    // Check if our character is alive. If not, create them
-    create_player(&mut state.ecs);
+/// How far behind the server remote entities are drawn. Can be set in local storage to
+/// trade smoothness on a bad connection for seeing things sooner on a good one.
+fn interpolation_delay() -> f64 {
+    stdweb::web::window()
+        .local_storage()
+        .get("interpolation_delay")
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(DEFAULT_INTERPOLATION_DELAY_MS)
+}
+
+/// Server to connect to, from the page's `?server=ws://...` query parameter
+fn server_url() -> Option<String> {
+    let url: Option<String> = js! {
+        return new URLSearchParams(window.location.search).get("server");
+    }
+    .try_into()
+    .unwrap_or_default();
+    url.filter(|url| !url.is_empty())
+}
+
+/// When `button` is clicked, take what was typed into `input` and queue it up as an event
+fn listen_to_button<F>(button: &str, input: &str, events: &EventQueue, to_event: F)
+where
+    F: Fn(String) -> ClientEvent + 'static,
+{
+    let input: InputElement = stdweb::web::document()
+        .query_selector(input)
+        .expect("Failed to query input")
+        .expect("Input not found")
+        .try_into()
+        .expect("Not an input element");
+    let events = events.clone();
+    stdweb::web::document()
+        .query_selector(button)
+        .expect("Failed to query button")
+        .expect("Button not found")
+        .add_event_listener(move |_: ClickEvent| {
+            events.push(to_event(input.raw_value()));
+            input.set_raw_value("");
+        });
 }
 
 fn main() {
     stdweb::initialize();
 
-    let width: i32 = 100;
-    let height: i32 = 100;
-    // TODO: Make this a better UUID. If two people join at the same time, they'll clash
+    // Only used when playing locally. The server assigns us an id when we connect.
     let player_id = format!("{}", Date::new().get_seconds());
 
-    let gs = Rc::new(RefCell::new(State { ecs: World::new() }));
+    let gs = Rc::new(RefCell::new(State::new()));
     let gui = Rc::new(RefCell::new(GUIComponents {
         fps_tracker: FPSTracker {
             for_time: 0,
@@ -173,39 +344,39 @@ fn main() {
             prev_fps: 0,
         },
     }));
-    initialize_ecs(
-        &mut gs.borrow_mut().ecs,
-        width,
-        height,
-        Date::new().get_seconds() as u64,
-        // 1,
-    );
-
-    js! {
-        var player_name = prompt("Please enter your crab's name");
-        window.localStorage.setItem("player_name", player_name);
-
-        // Clear save_state storage in case server is not up
-        window.localStorage.setItem("save_state", "");
-
-        // // Attempt to connect to server
-        // var socket = new WebSocket("ws://192.168.1.83:3012");
-
-        // socket.onmessage = function(event) {
-        //     console.log("save data received");
-        //     window.localStorage.setItem("save_state", event.data);
+    let world_config = WorldConfig {
+        seed: Date::new().get_seconds() as u64,
+        ..WorldConfig::default()
+    };
+    initialize_ecs(&mut gs.borrow_mut().ecs, &world_config, Prefabs::default());
+    gs.borrow_mut().ecs.insert(Prediction::default());
+    gs.borrow_mut()
+        .ecs
+        .insert(SnapshotBuffer::new(interpolation_delay()));
 
-        //     // Send player_input back
-        //     var player_input = window.localStorage.getItem("player_input");
-        //     if (player_input !== null && player_input != "") {
-        //         socket.send(player_input);
-        //         window.localStorage.setItem("player_input", "");
-        //     }
-        // };
+    let player_name: String = js! {
+        return prompt("Please enter your crab's name") || "";
     }
+    .try_into()
+    .unwrap_or_default();
+    gs.borrow_mut().ecs.insert(PlayerName(player_name));
+
+    // Connect to the server if we were given one, otherwise the world is all ours
+    let events = EventQueue::default();
+    let connection = match server_url() {
+        Some(url) => Connection::connect(&url, &events),
+        None => Connection::offline(),
+    };
+    network::show_status(connection.status());
+    gs.borrow_mut().ecs.insert(connection);
+    gs.borrow_mut().ecs.insert(events.clone());
 
     // Canvas is where we do all our rendering
-    let canvas = Canvas::new("#canvas", width as u32, height as u32);
+    let canvas = Canvas::new(
+        "#canvas",
+        world_config.width as u32,
+        world_config.height as u32,
+    );
     gs.borrow_mut().ecs.insert(canvas);
 
     // Insert the current player ID
@@ -219,6 +390,14 @@ fn main() {
         }
     });
 
+    // Chat and rename wait in the queue for the next tick like everything else
+    listen_to_button("#chat_button", "#chat_input", &events, |message| {
+        ClientEvent::Chat { message }
+    });
+    listen_to_button("#name_button", "#name_input", &events, |name| {
+        ClientEvent::Rename { name }
+    });
+
     // Link keystrokes to player input via stdweb
     stdweb::web::document().add_event_listener({
         let gs = gs.clone();
@@ -228,19 +407,26 @@ fn main() {
     });
 
     // Recurive main loop because that's the only way I've found to do it in stdweb
-    fn game_loop(gs: Rc<RefCell<State>>, gui: Rc<RefCell<GUIComponents>>, time: u32) {
+    fn game_loop(gs: Rc<RefCell<State>>, time: u32) {
         let gs = gs.clone();
-        let gui = gui.clone();
         stdweb::web::set_timeout(
             move || {
-                game_loop(gs.clone(), gui.clone(), 100);
-                rendering_tick(&mut gs.borrow_mut(), &mut gui.borrow_mut());
+                game_loop(gs.clone(), 100);
+                rendering_tick(&mut gs.borrow_mut());
             },
             time,
         );
     }
 
-    game_loop(gs, gui, 10);
+    fn draw_loop(gs: Rc<RefCell<State>>, gui: Rc<RefCell<GUIComponents>>) {
+        stdweb::web::window().request_animation_frame(move |_| {
+            draw_frame(&mut gs.borrow_mut(), &mut gui.borrow_mut());
+            draw_loop(gs, gui);
+        });
+    }
+
+    game_loop(gs.clone(), 10);
+    draw_loop(gs, gui);
 
     stdweb::event_loop();
 }
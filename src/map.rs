@@ -8,20 +8,39 @@ pub fn euclidean_distance(a: &Location, b: &Location) -> f64 {
     ((a.x - b.x).pow(2) as f64 + (a.y - b.y).pow(2) as f64).sqrt()
 }
 
+/// How close (in tiles) a crab has to be to reach something
+pub const PICKUP_DISTANCE: f64 = 5.0;
+
 /// Determine if two locations are close enough where we'd consider it reasonable for
 /// the player to pick up an item.
 pub fn within_pickup_distance(a: &Location, b: &Location) -> bool {
-    euclidean_distance(&a, &b) < 5.0
+    euclidean_distance(a, b) < PICKUP_DISTANCE
 }
 
-#[derive(PartialEq, Copy, Clone, Deserialize, Serialize)]
+/// Ordered from lowest to highest ground
+#[derive(PartialEq, Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum TileType {
     Void,
     Water,
+    /// Water right next to the beach, which crabs can wade through
+    ShallowWater,
     Sand,
     Grass,
 }
 
+/// Movement points it costs to step onto a tile, or None if it can't be walked on
+pub fn movement_cost(tile_type: TileType) -> Option<u32> {
+    match tile_type {
+        TileType::Void | TileType::Water => None,
+        TileType::ShallowWater => Some(4),
+        TileType::Sand => Some(2),
+        TileType::Grass => Some(1),
+    }
+}
+
+/// Cheapest tile to walk on, so pathfinding never overestimates the cost of a trip
+pub const MIN_MOVEMENT_COST: u32 = 1;
+
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct Map {
     pub width: i32,
@@ -81,7 +100,7 @@ impl Map {
         let pct_grass = 0.80;
         let pct_sand = (1_f64 - pct_grass) / 2_f64;
         for x in 0usize..(grass_map.width as usize - 1usize) {
-            for y in 0usize..(grass_map.width as usize - 1usize) {
+            for y in 0usize..(grass_map.height as usize - 1usize) {
                 if grass_map.tiles[x][y] != TileType::Water
                     && grass_map.tiles[x][y] != TileType::Void
                 {
@@ -117,32 +136,53 @@ impl Map {
             }
         }
 
+        add_shallow_water(&mut map);
+
         map
     }
+
+    /// Tile at the given location, or Void if it's off the map
+    pub fn tile_at(&self, x: i32, y: i32) -> TileType {
+        if x < 0 || x > self.width - 1 || y < 0 || y > self.height - 1 {
+            return TileType::Void;
+        }
+        self.tiles[x as usize][y as usize]
+    }
 }
 
-/// Given x+y, return true if an entity can walk there. False if it's water or outside map
-pub fn valid_walking_location(map: &Map, wants_to_move: &WantsToMoveTo) -> bool {
-    if wants_to_move.x < 0 || wants_to_move.x > map.width - 1 {
-        return false;
-    } else if wants_to_move.y < 0 || wants_to_move.y > map.height - 1 {
-        return false;
-    } else if map.tiles[wants_to_move.x as usize][wants_to_move.y as usize] == TileType::Water {
-        return false; // Cannot travel to water
-    } else if map.tiles[wants_to_move.x as usize][wants_to_move.y as usize] == TileType::Void {
-        return false; // Cannot travel to void
+/// Turn any water touching the beach into shallow water
+fn add_shallow_water(map: &mut Map) {
+    let mut new_tiles = map.tiles.clone();
+    for x in 0..map.width {
+        for y in 0..map.height {
+            if map.tiles[x as usize][y as usize] != TileType::Water {
+                continue;
+            }
+            let next_to_beach = (-1..=1)
+                .flat_map(|dx| (-1..=1).map(move |dy| (dx, dy)))
+                .any(|(dx, dy)| map.tile_at(x + dx, y + dy) == TileType::Sand);
+            if next_to_beach {
+                new_tiles[x as usize][y as usize] = TileType::ShallowWater;
+            }
+        }
     }
-    true
+    map.tiles = new_tiles;
 }
 
-/// Return a location which is not TileType::Water
-pub fn get_random_location_of_tile(
-    map: &Map,
-    rng: &mut Rand32,
-    tile_type: Option<TileType>,
-) -> Location {
-    let mut x;
-    let mut y;
+/// Given x+y, return true if an entity can walk there. False if it's deep water or outside map
+pub fn walkable_tile(map: &Map, x: i32, y: i32) -> bool {
+    movement_cost(map.tile_at(x, y)).is_some()
+}
+
+pub fn valid_walking_location(map: &Map, wants_to_move: &WantsToMoveTo) -> bool {
+    walkable_tile(map, wants_to_move.x, wants_to_move.y)
+}
+
+/// Random picks to try before looking through every tile for one that fits
+const RANDOM_LOCATION_ATTEMPTS: u32 = 100;
+
+/// Ranges of x and y to look for `tile_type` in
+fn spawn_ranges(map: &Map, tile_type: Option<TileType>) -> (Range<u32>, Range<u32>) {
     // Right side of island is where we spawn, so limit x range search for Sand
     let x_range = match tile_type {
         Some(TileType::Sand) => Range {
@@ -150,34 +190,70 @@ pub fn get_random_location_of_tile(
             end: map.width as u32 - 1,
         },
         _ => Range {
-            start: 1 as u32,
+            start: 1,
             end: map.width as u32 - 1,
         },
     };
     let y_range = Range {
-        start: 1 as u32,
-        end: map.width as u32 - 1,
+        start: 1,
+        end: map.height as u32 - 1,
     };
-    loop {
-        x = rng.rand_range(x_range.clone());
-        y = rng.rand_range(y_range.clone());
-        let cur_tile = map.tiles[x as usize][y as usize];
-        if tile_type.is_none() && cur_tile != TileType::Void && cur_tile != TileType::Water {
-            break;
-        } else if tile_type.is_some() && cur_tile == tile_type.unwrap() {
-            break;
+    (x_range, y_range)
+}
+
+/// True if the tile is `tile_type`, or isn't TileType::Void or TileType::Water if None
+fn is_spawn_tile(map: &Map, x: u32, y: u32, tile_type: Option<TileType>) -> bool {
+    let cur_tile = map.tiles[x as usize][y as usize];
+    match tile_type {
+        Some(tile_type) => cur_tile == tile_type,
+        None => cur_tile != TileType::Void && cur_tile != TileType::Water,
+    }
+}
+
+/// Every location get_random_location_of_tile could return
+pub fn locations_of_tile(map: &Map, tile_type: Option<TileType>) -> Vec<Location> {
+    let (x_range, y_range) = spawn_ranges(map, tile_type);
+    x_range
+        .flat_map(|x| y_range.clone().map(move |y| (x, y)))
+        .filter(|(x, y)| is_spawn_tile(map, *x, *y, tile_type))
+        .map(|(x, y)| Location {
+            x: x as i32,
+            y: y as i32,
+        })
+        .collect()
+}
+
+/// Return a random location of `tile_type`, or one which is not TileType::Water if None.
+/// None if the map doesn't have any.
+pub fn get_random_location_of_tile(
+    map: &Map,
+    rng: &mut Rand32,
+    tile_type: Option<TileType>,
+) -> Option<Location> {
+    let (x_range, y_range) = spawn_ranges(map, tile_type);
+    for _ in 0..RANDOM_LOCATION_ATTEMPTS {
+        let x = rng.rand_range(x_range.clone());
+        let y = rng.rand_range(y_range.clone());
+        if is_spawn_tile(map, x, y, tile_type) {
+            return Some(Location {
+                x: x as i32,
+                y: y as i32,
+            });
         }
     }
-    Location {
-        x: x as i32,
-        y: y as i32,
+    // There can't be many of them, so pick one of those there are rather than keep guessing
+    let mut locations = locations_of_tile(map, tile_type);
+    if locations.is_empty() {
+        return None;
     }
+    let index = rng.rand_range(0..locations.len() as u32) as usize;
+    Some(locations.swap_remove(index))
 }
 
 /// Modify the tiles structure to create something that looks like an island
 fn cellular_automata_map(map: &mut Map, rng: &mut Rand32, iterations: i32) {
     for x in 0usize..(map.width as usize - 1usize) {
-        for y in 0usize..(map.width as usize - 1usize) {
+        for y in 0usize..(map.height as usize - 1usize) {
             if x < 1 || x as i32 > map.width - 1 || y < 1 || y as i32 > map.height - 1 {
                 continue;
             }
@@ -195,7 +271,7 @@ fn cellular_automata_map(map: &mut Map, rng: &mut Rand32, iterations: i32) {
         let mut new_tiles = map.tiles.clone();
 
         for x in 0usize..(map.width as usize - 1usize) {
-            for y in 0usize..(map.width as usize - 1usize) {
+            for y in 0usize..(map.height as usize - 1usize) {
                 if map.tiles[x][y] == TileType::Void {
                     continue; // We should never place anything on the void
                 }
@@ -1,46 +1,104 @@
-use crate::components::{Location, WantsToMoveTo};
-use crate::map::Map;
+use crate::components::{BlocksTile, Location, Path, PlayerInfo, StandingOn, WantsToMoveTo};
+use crate::map::{movement_cost, Map};
+use crate::occupancy::Occupancy;
 use specs::prelude::*;
 
-pub struct MovementSystem {}
+#[derive(Default)]
+pub struct MovementSystem {
+    /// Only move this entity, for clients predicting where their own crab goes
+    pub only: Option<Entity>,
+}
 
 impl<'a> System<'a> for MovementSystem {
     type SystemData = (
+        Entities<'a>,
         ReadExpect<'a, Map>,
+        Write<'a, Occupancy>,
         WriteStorage<'a, Location>,
-        ReadStorage<'a, WantsToMoveTo>,
+        ReadStorage<'a, BlocksTile>,
+        WriteStorage<'a, WantsToMoveTo>,
+        WriteStorage<'a, Path>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (_map, mut locations, move_tos) = data;
-
-        for (mut location, move_to) in (&mut locations, &move_tos).join() {
-            let speed = move_to.speed as i32;
-            let dist_x = move_to.x - location.x;
-            let dist_y = move_to.y - location.y;
-            let mut new_x = location.x;
-            let mut new_y = location.y;
-            if dist_x == 0 && dist_y == 0 {
-                continue; // This entity has reached its desination
-            } else if dist_x.abs() > dist_y.abs() {
-                if speed > dist_x.abs() {
-                    new_x = move_to.x;
-                } else if dist_x > 0 {
-                    new_x += speed;
-                } else {
-                    new_x -= speed;
+        let (entities, map, mut occupancy, mut locations, blockers, mut move_tos, mut paths) = data;
+
+        let mut arrived = Vec::new();
+        let mut needs_new_path = Vec::new();
+        for (entity, location, move_to, path) in
+            (&entities, &mut locations, &move_tos, &mut paths).join()
+        {
+            if self.only.is_some_and(|only| only != entity) {
+                continue;
+            }
+            // Each tick we get `speed` movement points to spend on stepping along the path
+            path.movement_points += move_to.speed.max(1) as u32;
+            while let Some(next) = path.steps.first() {
+                // Something may have moved into our way since the path was worked out,
+                // so stop here and find a way around it next tick
+                if occupancy.is_blocked_for(next.x, next.y, entity) {
+                    needs_new_path.push(entity);
+                    break;
                 }
-            } else {
-                if speed > dist_y.abs() {
-                    new_y = move_to.y;
-                } else if dist_y > 0 {
-                    new_y += speed;
-                } else {
-                    new_y -= speed;
+                let cost = match movement_cost(map.tile_at(next.x, next.y)) {
+                    Some(cost) => cost,
+                    None => {
+                        needs_new_path.push(entity);
+                        break;
+                    }
+                };
+                if cost > path.movement_points {
+                    break; // Keep the points for next tick
                 }
+                path.movement_points -= cost;
+                let next = path.steps.remove(0);
+                if blockers.get(entity).is_some() {
+                    occupancy.move_blocker(entity, location, &next);
+                }
+                *location = next;
+            }
+            if path.steps.is_empty() {
+                arrived.push(entity); // This entity has reached its desination
+            }
+        }
+
+        for entity in needs_new_path {
+            paths.remove(entity);
+        }
+        for entity in arrived {
+            paths.remove(entity);
+            move_tos.remove(entity);
+        }
+    }
+}
+
+/// Keeps track of what each crab is standing on
+pub struct TerrainSystem {}
+
+impl<'a> System<'a> for TerrainSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Map>,
+        ReadStorage<'a, Location>,
+        ReadStorage<'a, PlayerInfo>,
+        WriteStorage<'a, StandingOn>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, map, locations, player_infos, mut standing_ons) = data;
+
+        for (entity, location, _) in (&entities, &locations, &player_infos).join() {
+            let tile_type = map.tile_at(location.x, location.y);
+            // Only touch the component when it changes so we don't send it every tick
+            if standing_ons
+                .get(entity)
+                .map(|standing_on| standing_on.tile_type)
+                != Some(tile_type)
+            {
+                standing_ons
+                    .insert(entity, StandingOn { tile_type })
+                    .expect("Unable to insert StandingOn");
             }
-            location.x = new_x;
-            location.y = new_y;
         }
     }
 }
@@ -0,0 +1,267 @@
+use ferris_chat::saveload_system::{
+    decode_server_message, serialize_player_input, PlayerInput, ServerMessage, WireFormat,
+};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use stdweb::traits::*;
+use stdweb::web::event::{
+    SocketCloseEvent, SocketErrorEvent, SocketMessageData, SocketMessageEvent, SocketOpenEvent,
+};
+use stdweb::web::{IEventTarget, SocketBinaryType, SocketReadyState, WebSocket};
+
+/// Wait before the first attempt to reconnect, doubled after every attempt that fails
+const INITIAL_RECONNECT_DELAY_MS: u32 = 500;
+/// Longest we'll wait between attempts to reconnect
+const MAX_RECONNECT_DELAY_MS: u32 = 30_000;
+
+/// Everything that happens outside the game loop which the game needs to know about
+pub enum ClientEvent {
+    Connected,
+    Disconnected {
+        reason: String,
+    },
+    /// Time to try connecting again
+    Reconnect,
+    Message(ServerMessage),
+    Chat {
+        message: String,
+    },
+    Rename {
+        name: String,
+    },
+}
+
+/// Events waiting for the next tick, in the order they happened. Browser callbacks push
+/// onto it and the game loop drains it, so nothing touches the ECS halfway through a tick.
+#[derive(Clone, Default)]
+pub struct EventQueue(Rc<RefCell<VecDeque<ClientEvent>>>);
+
+impl EventQueue {
+    pub fn push(&self, event: ClientEvent) {
+        self.0.borrow_mut().push_back(event);
+    }
+
+    pub fn drain(&self) -> Vec<ClientEvent> {
+        self.0.borrow_mut().drain(..).collect()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConnectionStatus {
+    /// No server to connect to, so we're running the world ourselves
+    Offline,
+    Connecting,
+    Connected,
+    /// Lost the connection, and trying again after `delay_ms`
+    Reconnecting {
+        reason: String,
+        delay_ms: u32,
+    },
+    /// Gave up on connecting at all
+    Disconnected {
+        reason: String,
+    },
+}
+
+impl ConnectionStatus {
+    pub fn describe(&self) -> String {
+        match self {
+            ConnectionStatus::Offline => String::from("Playing offline"),
+            ConnectionStatus::Connecting => String::from("Connecting..."),
+            ConnectionStatus::Connected => String::from("Connected"),
+            ConnectionStatus::Reconnecting { reason, delay_ms } => {
+                let retry = format!("reconnecting in {}s...", (delay_ms + 999) / 1000);
+                if reason.is_empty() {
+                    format!("Disconnected, {}", retry)
+                } else {
+                    format!("Disconnected ({}), {}", reason, retry)
+                }
+            }
+            ConnectionStatus::Disconnected { reason } if reason.is_empty() => {
+                String::from("Disconnected")
+            }
+            ConnectionStatus::Disconnected { reason } => format!("Disconnected: {}", reason),
+        }
+    }
+}
+
+/// Our WebSocket to the server, if we have one
+pub struct Connection {
+    url: String,
+    events: EventQueue,
+    socket: Option<WebSocket>,
+    status: ConnectionStatus,
+    /// Attempts to reconnect that have failed since we were last connected
+    failed_attempts: u32,
+    /// Given to us by the server so we can get our crab back if we lose the connection
+    resume_token: Option<String>,
+}
+
+/// Work out which format the server picked, going by the subprotocol it agreed to
+fn negotiated_format(socket: &WebSocket) -> WireFormat {
+    WireFormat::from_protocol_name(&socket.protocol()).unwrap_or(WireFormat::Json)
+}
+
+impl Connection {
+    /// Not connected to anything, for playing locally
+    pub fn offline() -> Connection {
+        Connection {
+            url: String::new(),
+            events: EventQueue::default(),
+            socket: None,
+            status: ConnectionStatus::Offline,
+            failed_attempts: 0,
+            resume_token: None,
+        }
+    }
+
+    /// Start connecting to the server at `url`. Everything the socket does from here on
+    /// ends up in `events`.
+    pub fn connect(url: &str, events: &EventQueue) -> Connection {
+        let mut connection = Connection {
+            url: url.to_string(),
+            events: events.clone(),
+            socket: None,
+            status: ConnectionStatus::Connecting,
+            failed_attempts: 0,
+            resume_token: None,
+        };
+        connection.open();
+        connection
+    }
+
+    fn open(&mut self) {
+        // Ask for every format we can read, and let the server pick the best it has
+        let protocols: Vec<&str> = WireFormat::PREFERRED
+            .iter()
+            .map(WireFormat::protocol_name)
+            .collect();
+        let socket = match WebSocket::new_with_protocols(&self.url, &protocols) {
+            Ok(socket) => socket,
+            Err(error) => {
+                // The address itself is bad, so trying again won't help
+                self.socket = None;
+                self.set_status(ConnectionStatus::Disconnected {
+                    reason: error.to_string(),
+                });
+                return;
+            }
+        };
+        socket.set_binary_type(SocketBinaryType::ArrayBuffer);
+
+        let events = &self.events;
+        socket.add_event_listener({
+            let events = events.clone();
+            move |_: SocketOpenEvent| events.push(ClientEvent::Connected)
+        });
+        socket.add_event_listener({
+            let events = events.clone();
+            move |event: SocketCloseEvent| {
+                events.push(ClientEvent::Disconnected {
+                    reason: event.reason(),
+                })
+            }
+        });
+        socket.add_event_listener(move |_: SocketErrorEvent| {
+            console!(error, "WebSocket error");
+        });
+        socket.add_event_listener({
+            let events = events.clone();
+            let socket = socket.clone();
+            move |event: SocketMessageEvent| {
+                let format = negotiated_format(&socket);
+                let decoded = match event.data() {
+                    SocketMessageData::Text(text) => decode_server_message(text.as_bytes(), format),
+                    SocketMessageData::ArrayBuffer(buffer) => {
+                        decode_server_message(&Vec::<u8>::from(buffer), format)
+                    }
+                    SocketMessageData::Blob(_) => {
+                        console!(error, "Got a Blob even though we asked for ArrayBuffers");
+                        return;
+                    }
+                };
+                match decoded {
+                    Ok(message) => events.push(ClientEvent::Message(message)),
+                    Err(error) => console!(error, format!("Bad server message: {}", error)),
+                }
+            }
+        });
+
+        self.socket = Some(socket);
+        self.set_status(ConnectionStatus::Connecting);
+    }
+
+    /// The socket is open, so pick up where we left off if we'd been connected before
+    pub fn on_connected(&mut self) {
+        self.failed_attempts = 0;
+        self.set_status(ConnectionStatus::Connected);
+        if let Some(token) = self.resume_token.clone() {
+            self.send(PlayerInput::Resume { token });
+        }
+    }
+
+    /// The socket closed, so wait a while and try again. Each failure waits twice as
+    /// long as the last so a server that's down isn't flooded with attempts.
+    pub fn on_disconnected(&mut self, reason: String) {
+        self.socket = None;
+        if let ConnectionStatus::Disconnected { .. } = self.status {
+            return; // We hung up on purpose
+        }
+        let delay_ms = INITIAL_RECONNECT_DELAY_MS
+            .saturating_mul(2u32.saturating_pow(self.failed_attempts))
+            .min(MAX_RECONNECT_DELAY_MS);
+        self.failed_attempts = self.failed_attempts.saturating_add(1);
+        self.set_status(ConnectionStatus::Reconnecting { reason, delay_ms });
+        let events = self.events.clone();
+        stdweb::web::set_timeout(move || events.push(ClientEvent::Reconnect), delay_ms);
+    }
+
+    /// Hang up for good, because connecting again wouldn't go any better
+    pub fn give_up(&mut self, reason: String) {
+        if let Some(socket) = self.socket.take() {
+            socket.close();
+        }
+        self.set_status(ConnectionStatus::Disconnected { reason });
+    }
+
+    pub fn reconnect(&mut self) {
+        if self.socket.is_none() {
+            self.open();
+        }
+    }
+
+    /// Remember the token to present if we have to reconnect
+    pub fn set_resume_token(&mut self, token: Option<String>) {
+        self.resume_token = token;
+    }
+
+    pub fn status(&self) -> &ConnectionStatus {
+        &self.status
+    }
+
+    fn set_status(&mut self, status: ConnectionStatus) {
+        show_status(&status);
+        self.status = status;
+    }
+
+    /// Send an input to the server. Anything sent while we aren't connected is dropped,
+    /// since the server wouldn't know who it was from anyway.
+    pub fn send(&self, input: PlayerInput) {
+        if let Some(socket) = &self.socket {
+            if socket.ready_state() == SocketReadyState::Open {
+                if socket.send_text(&serialize_player_input(input)).is_err() {
+                    console!(error, "Failed to send input to the server");
+                }
+            }
+        }
+    }
+}
+
+/// Show how we're connected under the canvas
+pub fn show_status(status: &ConnectionStatus) {
+    let text = status.describe();
+    js! {
+        document.getElementById("connection_status").textContent = @{text};
+    }
+}
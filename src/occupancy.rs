@@ -0,0 +1,58 @@
+use crate::components::{BlocksTile, Dead, Location};
+use specs::prelude::*;
+use std::collections::HashMap;
+
+/// Which tiles have something solid on them. Rebuilt at the start of every tick and kept
+/// up to date by MovementSystem as things move.
+#[derive(Default)]
+pub struct Occupancy {
+    blockers: HashMap<(i32, i32), Entity>,
+}
+
+impl Occupancy {
+    pub fn blocker_at(&self, x: i32, y: i32) -> Option<Entity> {
+        self.blockers.get(&(x, y)).cloned()
+    }
+
+    pub fn is_blocked(&self, x: i32, y: i32) -> bool {
+        self.blockers.contains_key(&(x, y))
+    }
+
+    /// Whether something other than `entity` is in the way
+    pub fn is_blocked_for(&self, x: i32, y: i32, entity: Entity) -> bool {
+        match self.blocker_at(x, y) {
+            Some(blocker) => blocker != entity,
+            None => false,
+        }
+    }
+
+    /// Record that a blocker moved so the rest of this tick sees where it is now
+    pub fn move_blocker(&mut self, entity: Entity, from: &Location, to: &Location) {
+        if self.blocker_at(from.x, from.y) == Some(entity) {
+            self.blockers.remove(&(from.x, from.y));
+        }
+        self.blockers.insert((to.x, to.y), entity);
+    }
+}
+
+pub struct OccupancySystem {}
+
+impl<'a> System<'a> for OccupancySystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Location>,
+        ReadStorage<'a, BlocksTile>,
+        ReadStorage<'a, Dead>,
+        Write<'a, Occupancy>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, locations, blockers, deads, mut occupancy) = data;
+
+        // Dead crabs are walked over rather than around
+        occupancy.blockers.clear();
+        for (entity, location, _, _) in (&entities, &locations, &blockers, !&deads).join() {
+            occupancy.blockers.insert((location.x, location.y), entity);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::components::{Location, Path, WantsToMoveTo};
+use crate::map::{movement_cost, walkable_tile, Map, MIN_MOVEMENT_COST};
+use crate::occupancy::Occupancy;
+use specs::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Most tiles we'll look at before giving up, so clicking somewhere unreachable on a
+/// big map doesn't search the whole thing
+const MAX_SEARCH_TILES: usize = 20_000;
+
+/// Whether `entity` could stand on this tile right now
+pub fn passable(map: &Map, occupancy: &Occupancy, x: i32, y: i32, entity: Entity) -> bool {
+    walkable_tile(map, x, y) && !occupancy.is_blocked_for(x, y, entity)
+}
+
+/// Tile waiting to be explored, ordered so the BinaryHeap pops the cheapest first
+#[derive(PartialEq, Eq)]
+struct OpenTile {
+    estimated_cost: u32,
+    tile: (i32, i32),
+}
+
+impl Ord for OpenTile {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimated_cost
+            .cmp(&self.estimated_cost)
+            .then_with(|| self.tile.cmp(&other.tile))
+    }
+}
+
+impl PartialOrd for OpenTile {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Lowest possible cost of getting from a to b, for the A* heuristic
+fn estimate_cost(a: (i32, i32), b: (i32, i32)) -> u32 {
+    ((a.0 - b.0).abs() + (a.1 - b.1).abs()) as u32 * MIN_MOVEMENT_COST
+}
+
+/// A* search over the map tiles, preferring cheap terrain. Crabs only walk along one axis
+/// at a time, so we only step to the four neighbouring tiles. Returns the tiles to walk
+/// through in order (not including `start`), or None if `goal` can't be reached.
+/// Anything blocking tiles other than `entity` itself is walked around.
+pub fn find_path(
+    map: &Map,
+    occupancy: &Occupancy,
+    entity: Entity,
+    start: &Location,
+    goal: &Location,
+) -> Option<Vec<Location>> {
+    let start = (start.x, start.y);
+    let goal = (goal.x, goal.y);
+    if !passable(map, occupancy, goal.0, goal.1, entity) {
+        return None;
+    }
+
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<(i32, i32), (i32, i32)> = HashMap::new();
+    let mut cost_so_far: HashMap<(i32, i32), u32> = HashMap::new();
+    open.push(OpenTile {
+        estimated_cost: estimate_cost(start, goal),
+        tile: start,
+    });
+    cost_so_far.insert(start, 0);
+
+    while let Some(OpenTile { tile, .. }) = open.pop() {
+        if tile == goal {
+            // Walk back from the goal to build the path
+            let mut path = Vec::new();
+            let mut current = goal;
+            while current != start {
+                path.push(Location {
+                    x: current.0,
+                    y: current.1,
+                });
+                current = came_from[&current];
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if cost_so_far.len() > MAX_SEARCH_TILES {
+            return None;
+        }
+
+        for (dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = (tile.0 + dx, tile.1 + dy);
+            if !passable(map, occupancy, next.0, next.1, entity) {
+                continue;
+            }
+            let cost = match movement_cost(map.tile_at(next.0, next.1)) {
+                Some(step_cost) => cost_so_far[&tile] + step_cost,
+                None => continue,
+            };
+            if cost_so_far.get(&next).is_none_or(|&known| cost < known) {
+                cost_so_far.insert(next, cost);
+                came_from.insert(next, tile);
+                open.push(OpenTile {
+                    estimated_cost: cost + estimate_cost(next, goal),
+                    tile: next,
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Works out how to get wherever entities want to move to
+#[derive(Default)]
+pub struct PathfindingSystem {
+    /// Only look after this entity, for clients predicting where their own crab goes
+    pub only: Option<Entity>,
+}
+
+impl<'a> System<'a> for PathfindingSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Map>,
+        Read<'a, Occupancy>,
+        ReadStorage<'a, Location>,
+        WriteStorage<'a, WantsToMoveTo>,
+        WriteStorage<'a, Path>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, map, occupancy, locations, mut move_tos, mut paths) = data;
+
+        let mut unreachable = Vec::new();
+        for (entity, location, move_to) in (&entities, &locations, &move_tos).join() {
+            if self.only.is_some_and(|only| only != entity) {
+                continue;
+            }
+            let destination = Location {
+                x: move_to.x,
+                y: move_to.y,
+            };
+            if let Some(path) = paths.get(entity) {
+                if path.destination == destination {
+                    continue; // Already on our way
+                }
+            }
+
+            match find_path(&map, &occupancy, entity, location, &destination) {
+                Some(steps) => {
+                    paths
+                        .insert(
+                            entity,
+                            Path {
+                                steps,
+                                destination,
+                                movement_points: 0,
+                            },
+                        )
+                        .expect("Unable to insert Path");
+                }
+                None => unreachable.push(entity),
+            }
+        }
+
+        // Nowhere to go, so stop trying
+        for entity in unreachable {
+            move_tos.remove(entity);
+            paths.remove(entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{get_random_location_of_tile, TileType};
+    use oorandom::Rand32;
+    use std::collections::VecDeque;
+
+    const SEEDS: [u64; 4] = [0, 1, 42, 1337];
+
+    fn new_entity() -> Entity {
+        World::new().create_entity().build()
+    }
+
+    fn is_neighbour(a: &Location, b: &Location) -> bool {
+        (a.x - b.x).abs() + (a.y - b.y).abs() == 1
+    }
+
+    /// Furthest tile we can walk to from `start`, found the slow way
+    fn furthest_walkable(map: &Map, start: &Location) -> Location {
+        let mut seen = HashMap::new();
+        let mut queue = VecDeque::new();
+        let mut furthest = (start.x, start.y);
+        seen.insert(furthest, ());
+        queue.push_back(furthest);
+        while let Some(tile) = queue.pop_front() {
+            furthest = tile;
+            for (dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let next = (tile.0 + dx, tile.1 + dy);
+                if walkable_tile(map, next.0, next.1) && !seen.contains_key(&next) {
+                    seen.insert(next, ());
+                    queue.push_back(next);
+                }
+            }
+        }
+        Location {
+            x: furthest.0,
+            y: furthest.1,
+        }
+    }
+
+    #[test]
+    fn finds_a_path_to_reachable_tiles() {
+        for seed in SEEDS.iter() {
+            let mut rng = Rand32::new(*seed);
+            let map = Map::new(&mut rng, 100, 100);
+            let start = get_random_location_of_tile(&map, &mut rng, Some(TileType::Grass)).unwrap();
+            let goal = furthest_walkable(&map, &start);
+
+            let path = find_path(&map, &Occupancy::default(), new_entity(), &start, &goal)
+                .expect("No path to a reachable tile");
+            assert_eq!(path.last(), Some(&goal));
+            let mut previous = &start;
+            for step in path.iter() {
+                assert!(is_neighbour(previous, step));
+                assert!(walkable_tile(&map, step.x, step.y));
+                previous = step;
+            }
+        }
+    }
+
+    #[test]
+    fn water_blocks_the_way() {
+        for seed in SEEDS.iter() {
+            let mut rng = Rand32::new(*seed);
+            let mut map = Map::new(&mut rng, 100, 100);
+            let start = get_random_location_of_tile(&map, &mut rng, Some(TileType::Grass)).unwrap();
+            let occupancy = Occupancy::default();
+            let entity = new_entity();
+
+            let water = get_random_location_of_tile(&map, &mut rng, Some(TileType::Water)).unwrap();
+            assert_eq!(find_path(&map, &occupancy, entity, &start, &water), None);
+
+            // Flood everything around a grass tile so it can't be walked to
+            let goal = get_random_location_of_tile(&map, &mut rng, Some(TileType::Grass)).unwrap();
+            if goal == start {
+                continue;
+            }
+            for (dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                map.tiles[(goal.x + dx) as usize][(goal.y + dy) as usize] = TileType::Water;
+            }
+            assert_eq!(find_path(&map, &occupancy, entity, &start, &goal), None);
+        }
+    }
+
+    /// Grass with a wall of water down the middle, leaving a gap at the bottom
+    fn walled_map(size: i32) -> Map {
+        let mut tiles = vec![vec![TileType::Grass; size as usize]; size as usize];
+        for y in 0..size as usize - 1 {
+            tiles[size as usize / 2][y] = TileType::Water;
+        }
+        Map {
+            width: size,
+            height: size,
+            tiles,
+        }
+    }
+
+    #[test]
+    fn gives_up_after_searching_too_many_tiles() {
+        let entity = new_entity();
+        let occupancy = Occupancy::default();
+        let start_and_goal = |size: i32| {
+            (
+                Location {
+                    x: size / 2 - 1,
+                    y: 0,
+                },
+                Location {
+                    x: size / 2 + 1,
+                    y: 0,
+                },
+            )
+        };
+
+        // Going round the wall only takes looking at a few thousand tiles on a small map
+        let small = walled_map(60);
+        let (start, goal) = start_and_goal(small.width);
+        assert!(find_path(&small, &occupancy, entity, &start, &goal).is_some());
+
+        // But on a big map we'd have to look at most of one side of it first
+        let big = walled_map(300);
+        let (start, goal) = start_and_goal(big.width);
+        assert_eq!(find_path(&big, &occupancy, entity, &start, &goal), None);
+    }
+}
@@ -0,0 +1,368 @@
+use crate::components::{Location, Path, WantsToMoveTo};
+use crate::entities::get_player_with_id;
+use crate::movement::MovementSystem;
+use crate::occupancy::OccupancySystem;
+use crate::pathfinding::PathfindingSystem;
+use crate::saveload_system::{load_game, AppliedSnapshot, OptimisticGameSave, ProtocolError};
+use crate::state::handle_click;
+use specs::prelude::*;
+use std::collections::VecDeque;
+
+/// Furthest we'll run our own crab ahead of the last snapshot from the server. If we
+/// haven't heard from it in this long we'd only be guessing.
+const MAX_PREDICTED_TICKS: u64 = 20;
+
+/// A click we've sent the server but haven't seen the result of yet
+#[derive(Clone)]
+struct PendingClick {
+    sequence: u32,
+    /// Frame we clicked on
+    frame: u64,
+    x: i32,
+    y: i32,
+}
+
+/// Where our own crab is headed, as the server last told us
+#[derive(Clone)]
+struct AuthoritativeCrab {
+    location: Location,
+    move_to: Option<WantsToMoveTo>,
+}
+
+/// Client side prediction of our own crab's movement in a remote session. We move our crab
+/// as soon as we click rather than waiting a round trip for the server to, and every time
+/// a snapshot arrives we start again from where the server says it is and replay whatever
+/// the server hasn't seen yet.
+#[derive(Default)]
+pub struct Prediction {
+    next_sequence: u32,
+    pending: VecDeque<PendingClick>,
+    /// Counts up once a tick, so we know how long ago each click was
+    frame: u64,
+    /// Frames since the last snapshot arrived
+    frames_since_snapshot: u64,
+    /// Ticks our crab runs ahead of the last snapshot. That's the round trip time, since
+    /// anything we do now reaches the server that long after the snapshot was taken.
+    lead: u64,
+    /// Kept so we can undo our predictions before applying the next delta, which is only
+    /// based on what the server sent us
+    authoritative: Option<AuthoritativeCrab>,
+}
+
+/// Run a single tick of movement for our crab alone
+fn simulate_tick(ecs: &mut World, crab: Entity) {
+    OccupancySystem {}.run_now(ecs);
+    PathfindingSystem { only: Some(crab) }.run_now(ecs);
+    MovementSystem { only: Some(crab) }.run_now(ecs);
+    ecs.maintain();
+}
+
+/// Put our crab back the way the server last told us it was
+fn undo_prediction(ecs: &mut World, crab: Entity) {
+    let authoritative = ecs.fetch::<Prediction>().authoritative.clone();
+    if let Some(authoritative) = authoritative {
+        ecs.write_storage::<Location>()
+            .insert(crab, authoritative.location)
+            .expect("Unable to restore Location");
+        let mut move_tos = ecs.write_storage::<WantsToMoveTo>();
+        match authoritative.move_to {
+            Some(move_to) => {
+                move_tos
+                    .insert(crab, move_to)
+                    .expect("Unable to restore WantsToMoveTo");
+            }
+            None => {
+                move_tos.remove(crab);
+            }
+        }
+    }
+    // Worked out from where we predicted we'd be, so no good any more
+    ecs.write_storage::<Path>().remove(crab);
+}
+
+fn remember_authoritative(ecs: &mut World, crab: Option<Entity>) {
+    let authoritative = crab.and_then(|crab| {
+        let location = ecs.read_storage::<Location>().get(crab)?.clone();
+        let move_to = ecs.read_storage::<WantsToMoveTo>().get(crab).cloned();
+        Some(AuthoritativeCrab { location, move_to })
+    });
+    ecs.fetch_mut::<Prediction>().authoritative = authoritative;
+}
+
+/// Walk our crab to where we clicked straight away, and remember the click until the
+/// server has seen it. Returns the sequence number to send the click with.
+pub fn predict_click(ecs: &mut World, x: i32, y: i32, player_id: &String) -> u32 {
+    handle_click(ecs, x, y, player_id);
+    let in_remote_session = ecs.fetch::<AppliedSnapshot>().tick.is_some();
+    let mut prediction = ecs.fetch_mut::<Prediction>();
+    let sequence = prediction.next_sequence;
+    prediction.next_sequence = prediction.next_sequence.wrapping_add(1);
+    if in_remote_session {
+        let frame = prediction.frame;
+        prediction.pending.push_back(PendingClick {
+            sequence,
+            frame,
+            x,
+            y,
+        });
+    }
+    sequence
+}
+
+/// Move our crab on by a tick in between snapshots
+pub fn predict_tick(ecs: &mut World, player_id: &String) {
+    {
+        let mut prediction = ecs.fetch_mut::<Prediction>();
+        prediction.frame += 1;
+        prediction.frames_since_snapshot += 1;
+        if prediction.lead + prediction.frames_since_snapshot > MAX_PREDICTED_TICKS {
+            return;
+        }
+    }
+    if let Some(crab) = get_player_with_id(ecs, player_id) {
+        simulate_tick(ecs, crab);
+    }
+}
+
+/// Apply a save from the server, then replay any clicks it hasn't seen yet on top of it to
+/// work out where our crab is by now. Returns false if the save didn't apply, and an error
+/// if it was malformed.
+pub fn load_game_predicted(
+    ecs: &mut World,
+    save_state: OptimisticGameSave,
+    player_id: &String,
+) -> Result<bool, ProtocolError> {
+    if let Some(crab) = get_player_with_id(ecs, player_id) {
+        undo_prediction(ecs, crab);
+    }
+    let last_input_sequence = save_state.last_input_sequence();
+    let loaded = load_game(ecs, save_state);
+    let applied = loaded == Ok(true);
+    let crab = get_player_with_id(ecs, player_id);
+    if applied {
+        remember_authoritative(ecs, crab);
+    }
+
+    let (pending, lead, frame) = {
+        let mut prediction = ecs.fetch_mut::<Prediction>();
+        if applied {
+            prediction.frames_since_snapshot = 0;
+            // Sequence numbers only count up, so anything up to the last one seen is done
+            // with. The newest of those tells us how long a round trip takes.
+            if let Some(last_sequence) = last_input_sequence {
+                let frame = prediction.frame;
+                if let Some(seen) = prediction
+                    .pending
+                    .iter()
+                    .rev()
+                    .find(|click| click.sequence <= last_sequence)
+                {
+                    prediction.lead = (frame - seen.frame).min(MAX_PREDICTED_TICKS);
+                }
+                prediction
+                    .pending
+                    .retain(|click| click.sequence > last_sequence);
+            }
+        }
+        // A click the server still hasn't seen means the round trip is at least that long
+        if let Some(oldest) = prediction.pending.front() {
+            let unseen_for = prediction.frame - oldest.frame;
+            prediction.lead = prediction.lead.max(unseen_for).min(MAX_PREDICTED_TICKS);
+        }
+        let pending: Vec<PendingClick> = prediction.pending.iter().cloned().collect();
+        (
+            pending,
+            prediction.lead + prediction.frames_since_snapshot,
+            prediction.frame,
+        )
+    };
+    let crab = match crab {
+        Some(crab) => crab,
+        None => return loaded,
+    };
+
+    // Replay every tick since the snapshot, clicking when we did last time. Anything
+    // clicked longer ago than that is clicked straight away.
+    let mut replayed = 0;
+    for ticks_ago in (0..lead.min(MAX_PREDICTED_TICKS)).rev() {
+        while replayed < pending.len() && frame - pending[replayed].frame > ticks_ago {
+            let click = &pending[replayed];
+            handle_click(ecs, click.x, click.y, player_id);
+            replayed += 1;
+        }
+        simulate_tick(ecs, crab);
+    }
+    for click in pending[replayed..].iter() {
+        handle_click(ecs, click.x, click.y, player_id);
+    }
+    loaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::spawn_crab;
+    use crate::map::{walkable_tile, Map};
+    use crate::occupancy::Occupancy;
+    use crate::pathfinding::find_path;
+    use crate::prefabs::Prefabs;
+    use crate::saveload_system::{
+        decode_server_message, diff_snapshots, package_save_state, PlayerInput, ServerMessage,
+        WireFormat,
+    };
+    use crate::state::{initialize_ecs, State, WorldConfig};
+
+    const PLAYER_ID: &str = "player-0";
+
+    fn new_world() -> State {
+        let mut gs = State::new();
+        initialize_ecs(&mut gs.ecs, &WorldConfig::default(), Prefabs::default());
+        gs
+    }
+
+    /// Full snapshot of the server, as the client would get it
+    fn save_state(server: &State, last_input_sequence: Option<u32>) -> OptimisticGameSave {
+        let snapshot = server.get_snapshot(server.current_tick());
+        let map = (*server.ecs.fetch::<Map>()).clone();
+        let encoded = package_save_state(
+            diff_snapshots(&[], &snapshot),
+            Some(map),
+            last_input_sequence,
+            WireFormat::Json,
+        )
+        .expect("Failed to encode save state");
+        match decode_server_message(&encoded, WireFormat::Json) {
+            Ok(ServerMessage::SaveState(save_state)) => save_state,
+            _ => panic!("Failed to decode save state"),
+        }
+    }
+
+    /// Places the player's crab can walk to but won't reach in a few ticks
+    fn far_off_destinations(server: &State) -> Vec<Location> {
+        let crab = get_player_with_id(&server.ecs, &PLAYER_ID.into()).unwrap();
+        let start = server
+            .ecs
+            .read_storage::<Location>()
+            .get(crab)
+            .unwrap()
+            .clone();
+        let map = server.ecs.fetch::<Map>();
+        (start.x - 30..start.x)
+            .map(|x| Location { x, y: start.y })
+            .filter(|location| walkable_tile(&map, location.x, location.y))
+            .filter(|location| {
+                find_path(&map, &Occupancy::default(), crab, &start, location).is_some()
+            })
+            .take(2)
+            .collect()
+    }
+
+    /// A server and a client which has just been sent everything on it
+    fn connected() -> (State, State) {
+        let mut server = new_world();
+        spawn_crab(&mut server.ecs, PLAYER_ID, "Ferris", false);
+        server.tick();
+        let mut client = new_world();
+        client.ecs.insert(Prediction::default());
+        let loaded = load_game_predicted(
+            &mut client.ecs,
+            save_state(&server, None),
+            &PLAYER_ID.into(),
+        );
+        assert_eq!(loaded, Ok(true));
+        (server, client)
+    }
+
+    /// Click on `destination` on the client, then let `ticks` go by
+    fn click_and_wait(client: &mut State, destination: &Location, ticks: u32) -> u32 {
+        let player_id = String::from(PLAYER_ID);
+        let sequence = predict_click(&mut client.ecs, destination.x, destination.y, &player_id);
+        for _ in 0..ticks {
+            predict_tick(&mut client.ecs, &player_id);
+        }
+        sequence
+    }
+
+    /// Where the client thinks its crab is going
+    fn destination(client: &State) -> Option<(i32, i32)> {
+        let crab = get_player_with_id(&client.ecs, &PLAYER_ID.into())?;
+        let move_tos = client.ecs.read_storage::<WantsToMoveTo>();
+        move_tos.get(crab).map(|move_to| (move_to.x, move_to.y))
+    }
+
+    fn pending_sequences(client: &State) -> Vec<u32> {
+        let prediction = client.ecs.fetch::<Prediction>();
+        prediction
+            .pending
+            .iter()
+            .map(|click| click.sequence)
+            .collect()
+    }
+
+    #[test]
+    fn clicks_the_server_has_not_seen_are_replayed() {
+        let (mut server, mut client) = connected();
+        let destinations = far_off_destinations(&server);
+        let first = click_and_wait(&mut client, &destinations[0], 3);
+        click_and_wait(&mut client, &destinations[1], 3);
+
+        // Only the first click has reached the server
+        server.handle_player_input(
+            &PLAYER_ID.into(),
+            PlayerInput::Click {
+                x: destinations[0].x,
+                y: destinations[0].y,
+                sequence: first,
+            },
+        );
+        server.tick();
+        let loaded = load_game_predicted(
+            &mut client.ecs,
+            save_state(&server, Some(first)),
+            &PLAYER_ID.into(),
+        );
+        assert_eq!(loaded, Ok(true));
+
+        assert_eq!(pending_sequences(&client), vec![first + 1]);
+        // The first click took six ticks to come back
+        assert_eq!(client.ecs.fetch::<Prediction>().lead, 6);
+        // The server has us heading for the first click, but we've clicked again since
+        assert_eq!(
+            destination(&client),
+            Some((destinations[1].x, destinations[1].y))
+        );
+    }
+
+    #[test]
+    fn clicks_the_server_has_seen_are_forgotten() {
+        let (mut server, mut client) = connected();
+        let destinations = far_off_destinations(&server);
+        click_and_wait(&mut client, &destinations[0], 3);
+        let last = click_and_wait(&mut client, &destinations[1], 3);
+
+        for (sequence, destination) in (0..=last).zip(destinations.iter()) {
+            server.handle_player_input(
+                &PLAYER_ID.into(),
+                PlayerInput::Click {
+                    x: destination.x,
+                    y: destination.y,
+                    sequence,
+                },
+            );
+        }
+        server.tick();
+        let loaded = load_game_predicted(
+            &mut client.ecs,
+            save_state(&server, Some(last)),
+            &PLAYER_ID.into(),
+        );
+        assert_eq!(loaded, Ok(true));
+
+        assert!(pending_sequences(&client).is_empty());
+        assert_eq!(client.ecs.fetch::<Prediction>().lead, 3);
+        assert_eq!(
+            destination(&client),
+            Some((destinations[1].x, destinations[1].y))
+        );
+    }
+}
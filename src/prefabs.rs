@@ -0,0 +1,195 @@
+use crate::components::*;
+use crate::map::TileType;
+use crate::saveload_system::with_components;
+use crate::spawner::ItemSpawnConfig;
+use serde::Deserialize;
+use specs::prelude::*;
+use specs::saveload::{MarkedBuilder, SimpleMarker};
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Prefabs every world is built from, unless the server is given its own
+const DEFAULT_PREFABS: &str = include_str!("../prefabs/prefabs.json");
+
+/// Prefabs the game spawns by name, so every set of prefabs has to have them
+const REQUIRED_PREFABS: [&str; 8] = [
+    "crab",
+    "chat_bubble",
+    "tree",
+    "poop",
+    "wave",
+    "drop_shadow",
+    "blood_splatter",
+    "mushroom_cloud",
+];
+
+/// Declares Prefab with a field for every networked component with a prefab name
+macro_rules! prefab_components {
+    (
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
+        /// Bundle of components to build an entity from. Anything that isn't a known
+        /// component is an error, so a typo doesn't silently spawn something half built.
+        #[derive(Clone, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct Prefab {
+            /// Tiles the prefab gets scattered over when the map is filled or an item
+            /// respawns. Anything other than deep water if unset.
+            pub spawn_tile: Option<TileType>,
+            $($( pub $prefab: Option<$type>, )?)*
+        }
+
+        impl Prefab {
+            /// Add every component the prefab has to `builder`
+            fn add_components<B: Builder>(&self, mut builder: B) -> B {
+                $($(
+                if let Some(component) = &self.$prefab {
+                    builder = builder.with(component.clone());
+                }
+                )?)*
+                builder
+            }
+        }
+    };
+}
+
+with_components!(prefab_components!());
+
+impl Prefab {
+    /// Make sure the components make sense together
+    fn validate(&self) -> Result<(), String> {
+        let drawn = self.text_renderable.is_some()
+            || self.chat_renderable.is_some()
+            || self.graphic_renderable.is_some();
+        if self.renderable.is_some() != drawn {
+            return Err(String::from(
+                "renderable needs a text_renderable, chat_renderable or graphic_renderable, \
+                 and vice versa",
+            ));
+        }
+        if self.graphic_animatable.is_some() && self.graphic_renderable.is_none() {
+            return Err(String::from(
+                "graphic_animatable needs a graphic_renderable",
+            ));
+        }
+        if self.item.is_some() != self.wants_to_be_picked_up.is_some() {
+            return Err(String::from(
+                "item and wants_to_be_picked_up have to go together",
+            ));
+        }
+        if self.wants_to_stab.is_some() && self.item.is_none() {
+            return Err(String::from("wants_to_stab needs an item to stab with"));
+        }
+        if let Some(item) = &self.item {
+            if self.renderable.is_none() {
+                return Err(String::from("item needs to be drawn"));
+            }
+            if self.blocks_tile.is_some() {
+                return Err(String::from("item can't block tiles"));
+            }
+            if item.kind.is_empty() {
+                return Err(String::from("item has no kind"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every prefab we know about, by name
+pub struct Prefabs {
+    prefabs: BTreeMap<String, Prefab>,
+}
+
+impl Default for Prefabs {
+    fn default() -> Prefabs {
+        Prefabs::from_json(DEFAULT_PREFABS).expect("Built in prefabs are invalid")
+    }
+}
+
+impl Prefabs {
+    /// Parse and validate a JSON object of prefabs keyed by name
+    pub fn from_json(json: &str) -> Result<Prefabs, String> {
+        let mut prefabs: BTreeMap<String, Prefab> =
+            serde_json::from_str(json).map_err(|e| format!("invalid prefabs: {}", e))?;
+        for (name, prefab) in prefabs.iter_mut() {
+            // Items remember which prefab they came from so the spawner can count them,
+            // and how they're drawn so they look the same when they're put back down
+            if let Some(item) = prefab.item.as_mut() {
+                item.kind = name.clone();
+                if let Some(renderable) = &prefab.renderable {
+                    item.ground_render_order = renderable.render_order;
+                }
+            }
+            prefab
+                .validate()
+                .map_err(|e| format!("invalid prefab {}: {}", name, e))?;
+        }
+        for name in REQUIRED_PREFABS.iter() {
+            if !prefabs.contains_key(*name) {
+                return Err(format!("missing prefab {}", name));
+            }
+        }
+        // Both get their text filled in when they're spawned
+        if prefabs["crab"].text_renderable.is_none() {
+            return Err(String::from(
+                "invalid prefab crab: needs a text_renderable for its name",
+            ));
+        }
+        if prefabs["chat_bubble"].chat_renderable.is_none() {
+            return Err(String::from(
+                "invalid prefab chat_bubble: needs a chat_renderable for the message",
+            ));
+        }
+        Ok(Prefabs { prefabs })
+    }
+
+    pub fn load_file(path: &str) -> Result<Prefabs, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("can't read prefabs {}: {}", path, e))?;
+        Prefabs::from_json(&contents)
+    }
+
+    /// Make sure everything we've been asked to spawn exists and is an item
+    pub fn validate_items(&self, items: &ItemSpawnConfig) -> Result<(), String> {
+        for name in items.targets.keys() {
+            match self.get(name) {
+                Some(prefab) if prefab.item.is_some() => {}
+                Some(_) => return Err(format!("prefab {} isn't an item", name)),
+                None => return Err(format!("missing prefab {}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.prefabs.get(name)
+    }
+
+    /// Start building an entity from the prefab called `name`. Anything else it needs,
+    /// like a Location or CarriedBy, can be added before it's built.
+    pub fn build<B: Builder + MarkedBuilder>(&self, name: &str, builder: B) -> B {
+        let prefab = self
+            .get(name)
+            .unwrap_or_else(|| panic!("Unknown prefab {}", name));
+        prefab.add_components(builder.marked::<SimpleMarker<EntityMarker>>())
+    }
+}
+
+/// Spawn the prefab called `name` at `location`
+pub fn spawn_prefab(ecs: &mut World, name: &str, location: Location) -> Entity {
+    let prefabs = ecs.fetch::<Prefabs>();
+    prefabs
+        .build(name, ecs.create_entity_unchecked())
+        .with(location)
+        .build()
+}
+
+/// Spawn the prefab called `name` following `owner` around
+pub fn spawn_prefab_carried_by(ecs: &mut World, name: &str, owner: Entity) -> Entity {
+    let prefabs = ecs.fetch::<Prefabs>();
+    prefabs
+        .build(name, ecs.create_entity_unchecked())
+        .with(CarriedBy { owner })
+        .build()
+}
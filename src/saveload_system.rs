@@ -5,19 +5,227 @@ use serde::{Deserialize, Serialize};
 use specs::error::NoError;
 use specs::prelude::*;
 use specs::saveload::{
-    DeserializeComponents, SerializeComponents, SimpleMarker, SimpleMarkerAllocator,
+    ConvertSaveload, DeserializeComponents, Marker, MarkerAllocator, SerializeComponents,
+    SimpleMarker, SimpleMarkerAllocator,
 };
+use specs::world::EntitiesRes;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
 #[derive(Default, Serialize, Deserialize, Clone)]
-struct OptimisticGameSave {
-    serialized_ecs: String,
-    maybe_serialized_map: Option<String>,
+pub struct OptimisticGameSave {
+    delta: SnapshotDelta,
+    maybe_map: Option<Map>,
+    /// Sequence number of the last click the server processed from this client before
+    /// the snapshot was taken
+    #[serde(default)]
+    last_input_sequence: Option<u32>,
+}
+
+impl OptimisticGameSave {
+    /// Tick of the snapshot this save brings the client up to
+    pub fn tick(&self) -> u64 {
+        self.delta.tick
+    }
+
+    pub fn last_input_sequence(&self) -> Option<u32> {
+        self.last_input_sequence
+    }
+
+    /// The map, which only comes with the first full snapshot
+    pub fn map(&self) -> Option<&Map> {
+        self.maybe_map.as_ref()
+    }
+}
+
+/// Encodings a client can negotiate (as a WebSocket subprotocol) for the saves we send it
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum WireFormat {
+    /// JSON text frames. Slow and large, but easy to read when debugging.
+    Json,
+    /// MessagePack binary frames
+    MessagePack,
+    /// LZ4 compressed MessagePack binary frames
+    CompressedMessagePack,
+}
+
+impl WireFormat {
+    /// Every format, best first
+    pub const PREFERRED: [WireFormat; 3] = [
+        WireFormat::CompressedMessagePack,
+        WireFormat::MessagePack,
+        WireFormat::Json,
+    ];
+
+    pub fn protocol_name(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "ferris-json",
+            WireFormat::MessagePack => "ferris-msgpack",
+            WireFormat::CompressedMessagePack => "ferris-msgpack-lz4",
+        }
+    }
+
+    pub fn from_protocol_name(name: &str) -> Option<WireFormat> {
+        match name.trim() {
+            "ferris-json" => Some(WireFormat::Json),
+            "ferris-msgpack" => Some(WireFormat::MessagePack),
+            "ferris-msgpack-lz4" => Some(WireFormat::CompressedMessagePack),
+            _ => None,
+        }
+    }
+
+    /// Whether saves in this format should be sent as binary rather than text frames
+    pub fn is_binary(&self) -> bool {
+        *self != WireFormat::Json
+    }
+}
+
+/// Stable id a networked component is sent as in snapshots
+pub type ComponentId = u16;
+
+/// Version of the component registry below. Bump it whenever a component is added,
+/// removed or moved between lists, so clients and saves built against a different set of
+/// components are turned away instead of misreading what we send them.
+pub const COMPONENT_REGISTRY_VERSION: u32 = 1;
+
+/// Every component in the game, declared once so registering, serializing and
+/// deserializing can't get out of step. Networked components are sent under their id,
+/// which must never change or be reused: new components take the next free id, and a
+/// removed component's id is retired with it. Networked components given a name with
+/// `as` can also be put in prefabs under that name. Local components are registered but
+/// never leave the machine they're on.
+///
+/// Retired ids: 1 (FPSTracker, now local)
+macro_rules! with_components {
+    ($callback:ident!($($args:tt)*)) => {
+        $callback! {
+            $($args)*
+            networked: [
+                2 => Location,
+                3 => PlayerInfo,
+                4 => Renderable as renderable,
+                5 => TextRenderable as text_renderable,
+                6 => ChatRenderable as chat_renderable,
+                7 => GraphicRenderable as graphic_renderable,
+                8 => GraphicAnimatable as graphic_animatable,
+                9 => WantsToMoveTo,
+                10 => Disappearing as disappearing,
+                11 => CarriedBy,
+                12 => CrabAI,
+                13 => WantsToBePickedUp as wants_to_be_picked_up,
+                14 => WantsToStab as wants_to_stab,
+                15 => Item as item,
+                16 => Health as health,
+                17 => Dead,
+                18 => BlocksTile as blocks_tile,
+                19 => StandingOn,
+                20 => AutoPickup as auto_pickup,
+                21 => Equipped,
+                22 => Idle
+            ],
+            local: [Path, FPSTracker]
+        }
+    };
+}
+pub(crate) use with_components;
+
+macro_rules! register_individually {
+    (
+        $ecs:expr;
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
+        $( $ecs.register::<$type>(); )*
+        $( $ecs.register::<$local>(); )*
+    };
+}
+
+macro_rules! networked_component_ids {
+    (
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
+        [$($id),*]
+    };
+}
+
+/// Id of every networked component
+const NETWORKED_COMPONENT_IDS: &[ComponentId] = &with_components!(networked_component_ids!());
+
+/// Register every component with the ECS
+pub fn register_components(ecs: &mut World) {
+    with_components!(register_individually!(ecs;));
+}
+
+/// Serialized components of a single entity, keyed by component id
+pub type EntitySnapshot = BTreeMap<ComponentId, serde_json::Value>;
+
+/// Every marked entity in the ECS at a given tick, keyed by marker id
+#[derive(Default, Clone)]
+pub struct WorldSnapshot {
+    pub tick: u64,
+    pub entities: BTreeMap<u64, EntitySnapshot>,
+}
+
+impl WorldSnapshot {
+    /// Only the entities with the given marker ids, for a client that doesn't need the rest
+    pub fn filtered(&self, interest: &BTreeSet<u64>) -> WorldSnapshot {
+        WorldSnapshot {
+            tick: self.tick,
+            entities: self
+                .entities
+                .iter()
+                .filter(|(id, _)| interest.contains(id))
+                .map(|(id, components)| (*id, components.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Changes required to bring a client from an older snapshot up to `tick`.
+/// A delta without a `base_tick` is a full snapshot and replaces everything.
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct SnapshotDelta {
+    pub base_tick: Option<u64>,
+    pub tick: u64,
+    pub changed: BTreeMap<u64, EntitySnapshot>,
+    pub removed_components: BTreeMap<u64, Vec<ComponentId>>,
+    pub deleted: BTreeSet<u64>,
+    /// Entities the client may not have, whose `changed` entry holds every component
+    #[serde(default)]
+    pub entered: BTreeSet<u64>,
+    /// Entities which still exist but the client no longer needs to know about
+    #[serde(default)]
+    pub left: BTreeSet<u64>,
+}
+
+impl SnapshotDelta {
+    /// Tell apart entities which were deleted from ones which just went out of the client's
+    /// area of interest, by looking them up in the snapshot of the whole `world`
+    pub fn separate_left(&mut self, world: &WorldSnapshot) {
+        let (left, deleted) = self
+            .deleted
+            .iter()
+            .partition(|id| world.entities.contains_key(id));
+        self.left = left;
+        self.deleted = deleted;
+    }
+}
+
+/// Tick of the last server snapshot applied to this ECS, if we're in a remote session
+#[derive(Default)]
+pub struct AppliedSnapshot {
+    pub tick: Option<u64>,
 }
 
 /// Magic stolen from "Roguelike Tutorial - In Rust" (See README.md)
 /// Macro to serialize ECS components and entities
 macro_rules! serialize_individually {
-    ($ecs:expr, $ser:expr, $data:expr, $( $type:ty),*) => {
+    (
+        $ecs:expr, $ser:expr, $data:expr;
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
         $(
         SerializeComponents::<NoError, SimpleMarker<EntityMarker>>::serialize(
             &( $ecs.read_storage::<$type>(), ),
@@ -39,48 +247,245 @@ pub fn serialize_ecs(ecs: &mut World) -> String {
 
     let mut writer = StringWriter::new();
     let mut serializer = serde_json::Serializer::new(&mut writer);
-    serialize_individually!(
-        ecs,
-        serializer,
-        data,
-        FPSTracker,
-        Location,
-        PlayerInfo,
-        Renderable,
-        TextRenderable,
-        ChatRenderable,
-        GraphicRenderable,
-        GraphicAnimatable,
-        WantsToMoveTo,
-        Disappearing,
-        CarriedBy,
-        CrabAI,
-        WantsToBePickedUp,
-        WantsToStab
-    );
+    with_components!(serialize_individually!(ecs, serializer, data;));
 
-    // TODO: Compress serde?
     writer.to_string()
 }
 
+/// Macro to convert ECS components into an EntitySnapshot per marked entity
+macro_rules! snapshot_individually {
+    (
+        $ecs:expr, $snapshot:expr;
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
+        $(
+        {
+            let entities = $ecs.entities();
+            let markers = $ecs.read_storage::<SimpleMarker<EntityMarker>>();
+            let storage = $ecs.read_storage::<$type>();
+            for (marker, component) in (&markers, &storage).join() {
+                let data = <$type as ConvertSaveload<SimpleMarker<EntityMarker>>>::convert_into(
+                    component,
+                    |entity| markers.get(entity).cloned(),
+                )
+                .unwrap();
+                $snapshot
+                    .entities
+                    .entry(marker.id())
+                    .or_default()
+                    .insert($id, serde_json::to_value(&data).unwrap());
+            }
+            // Keep entities which are marked but have none of these components
+            for (_, marker) in (&entities, &markers).join() {
+                $snapshot.entities.entry(marker.id()).or_default();
+            }
+        }
+        )*
+    };
+}
+
+/// Take a snapshot of every marked entity so it can be diffed against later snapshots
+pub fn snapshot_ecs(ecs: &World, tick: u64) -> WorldSnapshot {
+    let mut snapshot = WorldSnapshot {
+        tick,
+        entities: BTreeMap::new(),
+    };
+    with_components!(snapshot_individually!(ecs, snapshot;));
+    snapshot
+}
+
+/// Build the delta which brings any one of `bases` up to `target`.
+/// We diff against every snapshot the client may currently be showing (everything sent since
+/// its last acknowledgement) so the delta applies cleanly regardless of which one it has.
+/// No bases means the client has nothing, so the delta is a full snapshot.
+pub fn diff_snapshots(bases: &[&WorldSnapshot], target: &WorldSnapshot) -> SnapshotDelta {
+    let mut delta = SnapshotDelta {
+        base_tick: bases.first().map(|base| base.tick),
+        tick: target.tick,
+        ..Default::default()
+    };
+
+    for (id, components) in target.entities.iter() {
+        let mut changed = EntitySnapshot::new();
+        for (component_id, value) in components.iter() {
+            let is_changed = bases.is_empty()
+                || bases.iter().any(|base| {
+                    base.entities
+                        .get(id)
+                        .and_then(|base_components| base_components.get(component_id))
+                        != Some(value)
+                });
+            if is_changed {
+                changed.insert(*component_id, value.clone());
+            }
+        }
+        let is_new = bases.is_empty() || bases.iter().any(|base| !base.entities.contains_key(id));
+        if is_new || !changed.is_empty() {
+            delta.changed.insert(*id, changed);
+        }
+        if is_new && !bases.is_empty() {
+            delta.entered.insert(*id);
+        }
+
+        let mut removed = BTreeSet::new();
+        for base in bases.iter() {
+            if let Some(base_components) = base.entities.get(id) {
+                for component_id in base_components.keys() {
+                    if !components.contains_key(component_id) {
+                        removed.insert(*component_id);
+                    }
+                }
+            }
+        }
+        if !removed.is_empty() {
+            delta
+                .removed_components
+                .insert(*id, removed.into_iter().collect());
+        }
+    }
+
+    for base in bases.iter() {
+        for id in base.entities.keys() {
+            if !target.entities.contains_key(id) {
+                delta.deleted.insert(*id);
+            }
+        }
+    }
+
+    delta
+}
+
 pub fn serialize_map(map: &Map) -> String {
     serde_json::to_string(&map).unwrap()
 }
 
-/// Package our save state into a struct that optionally includes the map.
+/// Everything the server sends to a client
+#[derive(Serialize, Deserialize)]
+pub enum ServerMessage {
+    /// Sent on connect with the id of the crab this connection controls, and again if
+    /// the client resumes an earlier session
+    Welcome {
+        player_id: String,
+        /// Presented on reconnecting to take back the same crab
+        #[serde(default)]
+        resume_token: Option<String>,
+        /// COMPONENT_REGISTRY_VERSION of the server, which the client has to match
+        #[serde(default)]
+        component_registry_version: u32,
+        /// Milliseconds between the server's ticks, so the client can tell how far apart
+        /// the snapshots it's sent are
+        #[serde(default)]
+        tick_interval_ms: f64,
+    },
+    SaveState(OptimisticGameSave),
+    /// Sent when we couldn't make sense of something the client sent us
+    ProtocolError {
+        message: String,
+    },
+    /// Sent to both the killer and the victim, using their crab names
+    PlayerKilled {
+        killer: String,
+        victim: String,
+    },
+}
+
+/// Largest player input we're willing to parse. Nothing legitimate comes close.
+pub const MAX_PLAYER_INPUT_BYTES: usize = 1024;
+
+/// Reasons a message received over the network couldn't be used
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    /// The message was larger than we allow
+    TooLarge { size: usize, max: usize },
+    /// The message wasn't valid in the expected encoding
+    Malformed(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::TooLarge { size, max } => {
+                write!(f, "message of {} bytes is larger than {} bytes", size, max)
+            }
+            ProtocolError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A message we meant to send couldn't be encoded
+#[derive(Debug)]
+pub struct EncodeError(String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to encode message: {}", self.0)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Encode a server message in the format the client negotiated
+pub fn encode_server_message(
+    message: &ServerMessage,
+    format: WireFormat,
+) -> Result<Vec<u8>, EncodeError> {
+    let failed = |error: &dyn fmt::Display| EncodeError(error.to_string());
+    match format {
+        WireFormat::Json => serde_json::to_vec(message).map_err(|e| failed(&e)),
+        WireFormat::MessagePack => rmp_serde::to_vec(message).map_err(|e| failed(&e)),
+        WireFormat::CompressedMessagePack => {
+            let encoded = rmp_serde::to_vec(message).map_err(|e| failed(&e))?;
+            Ok(lz4_flex::compress_prepend_size(&encoded))
+        }
+    }
+}
+
+/// Inverse of encode_server_message
+pub fn decode_server_message(
+    message: &[u8],
+    format: WireFormat,
+) -> Result<ServerMessage, ProtocolError> {
+    let malformed = |error: &dyn fmt::Display| ProtocolError::Malformed(error.to_string());
+    match format {
+        WireFormat::Json => serde_json::from_slice(message).map_err(|e| malformed(&e)),
+        WireFormat::MessagePack => rmp_serde::from_slice(message).map_err(|e| malformed(&e)),
+        WireFormat::CompressedMessagePack => {
+            let decompressed =
+                lz4_flex::decompress_size_prepended(message).map_err(|e| malformed(&e))?;
+            rmp_serde::from_slice(&decompressed).map_err(|e| malformed(&e))
+        }
+    }
+}
+
+/// Package our snapshot delta into a struct that optionally includes the map.
 /// This allows us to only send the large map data when the client's map needs to be updated
-pub fn package_save_state(serialized_ecs: String, maybe_serialized_map: Option<String>) -> String {
-    serde_json::to_string(&OptimisticGameSave {
-        serialized_ecs,
-        maybe_serialized_map,
-    })
-    .unwrap()
+pub fn package_save_state(
+    delta: SnapshotDelta,
+    maybe_map: Option<Map>,
+    last_input_sequence: Option<u32>,
+    format: WireFormat,
+) -> Result<Vec<u8>, EncodeError> {
+    encode_server_message(
+        &ServerMessage::SaveState(OptimisticGameSave {
+            delta,
+            maybe_map,
+            last_input_sequence,
+        }),
+        format,
+    )
 }
 
 /// Magic stolen from "Roguelike Tutorial - In Rust" (See README.md)
 /// Macro to deserialize ECS components and entities
 macro_rules! deserialize_individually {
-    ($ecs:expr, $de:expr, $data:expr, $( $type:ty),*) => {
+    (
+        $ecs:expr, $de:expr, $data:expr;
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
         $(
         DeserializeComponents::<NoError, _>::deserialize(
             &mut ( &mut $ecs.write_storage::<$type>(), ),
@@ -88,80 +493,525 @@ macro_rules! deserialize_individually {
             &mut $data.1, // marker
             &mut $data.2, // allocater
             &mut $de,
-        )
-        .unwrap();
+        )?;
         )*
     };
 }
 
-/// Update the ECS to reflect the world deserialized from the OptimisticGameSave JSON string
-pub fn load_game(ecs: &mut World, package_save_str: String) {
+/// Inverse of serialize_ecs. Adds the serialized entities to the ECS, or fails if
+/// `serialized_ecs` is cut short or doesn't match the components we have.
+pub fn deserialize_ecs(ecs: &mut World, serialized_ecs: &str) -> serde_json::Result<()> {
+    let mut de = serde_json::Deserializer::from_str(serialized_ecs);
     {
-        // Delete everything
+        let mut d = (
+            &mut ecs.entities(),
+            &mut ecs.write_storage::<SimpleMarker<EntityMarker>>(),
+            &mut ecs.write_resource::<SimpleMarkerAllocator<EntityMarker>>(),
+        );
+
+        with_components!(deserialize_individually!(ecs, de, d;));
+    }
+    de.end()
+}
+
+/// Macro to insert (or remove when None) a single component from a snapshot delta by id
+macro_rules! apply_individually {
+    (
+        $ecs:expr, $entity:expr, $component_id:expr, $maybe_value:expr;
+        networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+        local: [$($local:ty),*]
+    ) => {
+        match $component_id {
+            $(
+            $id => match $maybe_value {
+                Some(value) => {
+                    let data: <$type as ConvertSaveload<SimpleMarker<EntityMarker>>>::Data =
+                        serde_json::from_value(value).map_err(|e| {
+                            ProtocolError::Malformed(format!("component {}: {}", $id, e))
+                        })?;
+                    let entities = $ecs.entities();
+                    let mut markers = $ecs.write_storage::<SimpleMarker<EntityMarker>>();
+                    let mut allocator = $ecs.write_resource::<SimpleMarkerAllocator<EntityMarker>>();
+                    let component = <$type as ConvertSaveload<SimpleMarker<EntityMarker>>>::convert_from(
+                        data,
+                        |marker| Some(entity_for_marker_id(marker.id(), &entities, &mut markers, &mut allocator)),
+                    )
+                    .unwrap_or_else(|error| match error {});
+                    $ecs.write_storage::<$type>()
+                        .insert($entity, component)
+                        .map_err(|e| {
+                            ProtocolError::Malformed(format!("component {}: {}", $id, e))
+                        })?;
+                }
+                None => {
+                    $ecs.write_storage::<$type>().remove($entity);
+                }
+            },
+            )*
+            _ => {
+                return Err(ProtocolError::Malformed(format!(
+                    "unknown component {}",
+                    $component_id
+                )))
+            }
+        }
+    };
+}
+
+/// Find the entity with the given marker id, creating it if we haven't seen it before
+fn entity_for_marker_id(
+    id: u64,
+    entities: &EntitiesRes,
+    markers: &mut WriteStorage<SimpleMarker<EntityMarker>>,
+    allocator: &mut SimpleMarkerAllocator<EntityMarker>,
+) -> Entity {
+    if let Some(entity) = allocator.retrieve_entity_internal(id) {
+        if markers.get(entity).map(|marker| marker.id()) == Some(id) {
+            return entity;
+        }
+    }
+    let entity = entities.create();
+    let marker = allocator.allocate(entity, Some(id));
+    markers
+        .insert(entity, marker)
+        .expect("Failed to mark entity");
+    entity
+}
+
+/// Apply a single component change from a snapshot delta to the given entity
+fn apply_component(
+    ecs: &mut World,
+    entity: Entity,
+    component_id: ComponentId,
+    maybe_value: Option<serde_json::Value>,
+) -> Result<(), ProtocolError> {
+    with_components!(apply_individually!(ecs, entity, component_id, maybe_value;));
+    Ok(())
+}
+
+/// Whether a delta can be applied on top of the snapshot we're currently showing
+fn can_apply_delta(ecs: &World, delta: &SnapshotDelta) -> bool {
+    let applied_tick = ecs.fetch::<AppliedSnapshot>().tick;
+    match (delta.base_tick, applied_tick) {
+        (None, _) => true,
+        (Some(base_tick), Some(applied_tick)) => {
+            base_tick <= applied_tick && applied_tick < delta.tick
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// Make sure the server only sent us components we know about, before we change anything
+fn check_component_ids(delta: &SnapshotDelta) -> Result<(), ProtocolError> {
+    let component_ids = delta
+        .changed
+        .values()
+        .flat_map(|components| components.keys())
+        .chain(delta.removed_components.values().flatten());
+    for component_id in component_ids {
+        if !NETWORKED_COMPONENT_IDS.contains(component_id) {
+            return Err(ProtocolError::Malformed(format!(
+                "unknown component {}",
+                component_id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Update the ECS to reflect the snapshot delta. If a component turns out to be malformed
+/// partway through, we stay on the snapshot we had so the next delta from there puts
+/// right anything this one got wrong.
+fn apply_snapshot_delta(ecs: &mut World, delta: SnapshotDelta) -> Result<(), ProtocolError> {
+    check_component_ids(&delta)?;
+    {
+        // Delete entities the server no longer has. A full snapshot replaces every marked entity.
         let mut to_delete = Vec::new();
-        for e in ecs.entities().join() {
-            to_delete.push(e);
+        {
+            let entities = ecs.entities();
+            let markers = ecs.read_storage::<SimpleMarker<EntityMarker>>();
+            for (entity, marker) in (&entities, &markers).join() {
+                if delta.base_tick.is_none()
+                    || delta.deleted.contains(&marker.id())
+                    || delta.left.contains(&marker.id())
+                {
+                    to_delete.push(entity);
+                }
+            }
         }
         for del in to_delete.iter() {
             ecs.delete_entity(*del).expect("Deletion failed");
         }
+        // Forget the markers of deleted entities
+        let entities = ecs.entities();
+        let markers = ecs.read_storage::<SimpleMarker<EntityMarker>>();
+        ecs.write_resource::<SimpleMarkerAllocator<EntityMarker>>()
+            .maintain(&entities, &markers);
     }
 
-    // Extract our save package from the string
-    let package_save_state: OptimisticGameSave = serde_json::from_str(&package_save_str).unwrap();
-
-    // Deserialize the ECS because we know that will be there
-    let mut de = serde_json::Deserializer::from_str(&package_save_state.serialized_ecs);
-    {
-        let mut d = (
-            &mut ecs.entities(),
+    for (id, components) in delta.changed {
+        let entity = entity_for_marker_id(
+            id,
+            &ecs.entities(),
             &mut ecs.write_storage::<SimpleMarker<EntityMarker>>(),
             &mut ecs.write_resource::<SimpleMarkerAllocator<EntityMarker>>(),
         );
+        // We may still have an out of date copy of an entity coming back into view (or a
+        // stand in for one something referred to), so drop anything it no longer has
+        if delta.entered.contains(&id) {
+            for component_id in NETWORKED_COMPONENT_IDS {
+                if !components.contains_key(component_id) {
+                    apply_component(ecs, entity, *component_id, None)?;
+                }
+            }
+        }
+        for (component_id, value) in components {
+            apply_component(ecs, entity, component_id, Some(value))?;
+        }
+    }
 
-        deserialize_individually!(
-            ecs,
-            de,
-            d,
-            FPSTracker,
-            Location,
-            PlayerInfo,
-            Renderable,
-            TextRenderable,
-            ChatRenderable,
-            GraphicRenderable,
-            GraphicAnimatable,
-            WantsToMoveTo,
-            Disappearing,
-            CarriedBy,
-            CrabAI,
-            WantsToBePickedUp,
-            WantsToStab
-        );
+    for (id, component_ids) in delta.removed_components {
+        let maybe_entity = ecs
+            .read_resource::<SimpleMarkerAllocator<EntityMarker>>()
+            .retrieve_entity_internal(id);
+        if let Some(entity) = maybe_entity {
+            for component_id in component_ids {
+                apply_component(ecs, entity, component_id, None)?;
+            }
+        }
+    }
+
+    ecs.maintain();
+    ecs.write_resource::<AppliedSnapshot>().tick = Some(delta.tick);
+    Ok(())
+}
+
+/// Update the ECS to reflect the world in an OptimisticGameSave package.
+/// Returns false if the delta didn't apply on top of the snapshot we currently have,
+/// or an error if the server sent us something we couldn't make sense of.
+pub fn load_game(
+    ecs: &mut World,
+    package_save_state: OptimisticGameSave,
+) -> Result<bool, ProtocolError> {
+    if !can_apply_delta(ecs, &package_save_state.delta) {
+        return Ok(false);
+    }
+    let applied = apply_snapshot_delta(ecs, package_save_state.delta);
+    if applied.is_err() {
+        // Tidy up whatever we got through before finding the problem
+        ecs.maintain();
     }
+    applied?;
 
     // If the map was in this package, copy the map over to our instance
-    if let Some(serialized_map) = package_save_state.maybe_serialized_map {
-        let mut map_ref = ecs.write_resource::<Map>();
-        let new_map: Map = serde_json::from_str(&serialized_map).unwrap();
-        *map_ref = new_map.clone();
+    if let Some(new_map) = package_save_state.maybe_map {
+        *ecs.write_resource::<Map>() = new_map;
     }
+    Ok(true)
 }
 
-#[derive(Serialize, Deserialize)]
+/// Everything a client sends to the server. The server knows which player each
+/// connection belongs to, so inputs don't say who they're from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PlayerInput {
-    CreatePlayer { id: String, name: String },
-    DeletePlayer { id: String },
-    ChangeName { id: String, name: String },
-    SpecialInput { id: String, input: String },
-    Click { id: String, x: i32, y: i32 },
-    Chat { id: String, message: String },
+    CreatePlayer {
+        name: String,
+    },
+    DeletePlayer,
+    ChangeName {
+        name: String,
+    },
+    SpecialInput {
+        input: String,
+    },
+    Click {
+        x: i32,
+        y: i32,
+        /// Counts up with every click so the client knows which ones the server has seen
+        #[serde(default)]
+        sequence: u32,
+    },
+    Chat {
+        message: String,
+    },
+    AckSnapshot {
+        tick: u64,
+    },
+    /// Take back the crab from a connection we lost, using the token it was welcomed with
+    Resume {
+        token: String,
+    },
+    /// Pick up the closest item in reach
+    PickUp,
+    /// Put down everything we're carrying
+    Drop,
+    /// Hand everything we're carrying to the closest crab in reach
+    Give,
+    /// Equip the next item in our inventory that goes in this slot
+    Swap {
+        slot: EquipmentSlot,
+    },
+    /// Put whatever's in this slot away
+    Unequip {
+        slot: EquipmentSlot,
+    },
+    SetAutoPickup {
+        enabled: bool,
+    },
 }
 
 pub fn serialize_player_input(player_input: PlayerInput) -> String {
     serde_json::to_string(&player_input).unwrap()
 }
 
-pub fn deserialize_player_input(player_input_str: String) -> PlayerInput {
-    serde_json::from_str(&player_input_str).unwrap()
+pub fn deserialize_player_input(player_input_str: &str) -> Result<PlayerInput, ProtocolError> {
+    if player_input_str.len() > MAX_PLAYER_INPUT_BYTES {
+        return Err(ProtocolError::TooLarge {
+            size: player_input_str.len(),
+            max: MAX_PLAYER_INPUT_BYTES,
+        });
+    }
+    serde_json::from_str(player_input_str).map_err(|e| ProtocolError::Malformed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::spawn_crab;
+    use crate::prefabs::Prefabs;
+    use crate::state::{initialize_ecs, State, WorldConfig};
+    use specs::shred::MetaTable;
+    use specs::storage::{AnyStorage, MaskedStorage};
+
+    fn new_world() -> State {
+        let mut gs = State::new();
+        initialize_ecs(&mut gs.ecs, &WorldConfig::default(), Prefabs::default());
+        gs
+    }
+
+    /// Send a full snapshot of a running world through `format` and load it into a
+    /// fresh client, which should end up with exactly what the server has
+    fn round_trip(format: WireFormat) {
+        let mut server = new_world();
+        spawn_crab(&mut server.ecs, "player-0", "Ferris", false);
+        server.tick();
+        let snapshot = server.get_snapshot(server.current_tick());
+        let map = (*server.ecs.fetch::<Map>()).clone();
+
+        let encoded = package_save_state(
+            diff_snapshots(&[], &snapshot),
+            Some(map.clone()),
+            Some(7),
+            format,
+        )
+        .expect("Failed to encode save state");
+        let save_state = match decode_server_message(&encoded, format) {
+            Ok(ServerMessage::SaveState(save_state)) => save_state,
+            Ok(_) => panic!("Decoded the wrong message"),
+            Err(error) => panic!("Failed to decode save state: {}", error),
+        };
+        assert_eq!(save_state.tick(), snapshot.tick);
+        assert_eq!(save_state.last_input_sequence(), Some(7));
+
+        let mut client = new_world();
+        assert_eq!(load_game(&mut client.ecs, save_state), Ok(true));
+        let loaded = client.get_snapshot(snapshot.tick);
+        assert_eq!(loaded.entities, snapshot.entities);
+        assert_eq!(
+            serialize_map(&client.ecs.fetch::<Map>()),
+            serialize_map(&map)
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(WireFormat::Json);
+    }
+
+    #[test]
+    fn message_pack_round_trip() {
+        round_trip(WireFormat::MessagePack);
+    }
+
+    #[test]
+    fn compressed_message_pack_round_trip() {
+        round_trip(WireFormat::CompressedMessagePack);
+    }
+
+    macro_rules! remove_storages {
+        (
+            $ecs:expr;
+            networked: [$($id:literal => $type:ty $(as $prefab:ident)?),*],
+            local: [$($local:ty),*]
+        ) => {
+            $(
+            assert!(
+                $ecs.remove::<MaskedStorage<$type>>().is_some(),
+                "{} isn't registered",
+                stringify!($type)
+            );
+            )*
+            $(
+            assert!(
+                $ecs.remove::<MaskedStorage<$local>>().is_some(),
+                "{} isn't registered",
+                stringify!($local)
+            );
+            )*
+        };
+    }
+
+    /// Anything registered outside with_components!, say by a system's setup, would never
+    /// be saved or sent to clients
+    #[test]
+    fn every_registered_component_is_in_the_registry() {
+        let mut gs = new_world();
+        with_components!(remove_storages!(gs.ecs;));
+        // Along with the marker every saved entity is identified by
+        gs.ecs
+            .remove::<MaskedStorage<SimpleMarker<EntityMarker>>>()
+            .expect("Markers aren't registered");
+        // Whatever's left wasn't in the registry
+        let unlisted = gs
+            .ecs
+            .fetch::<MetaTable<dyn AnyStorage>>()
+            .iter(&gs.ecs)
+            .count();
+        assert_eq!(
+            unlisted, 0,
+            "{} components are registered outside with_components!",
+            unlisted
+        );
+    }
+
+    #[test]
+    fn truncated_saves_are_rejected() {
+        let mut server = new_world();
+        let serialized_ecs = serialize_ecs(&mut server.ecs);
+        assert!(deserialize_ecs(&mut new_world().ecs, &serialized_ecs).is_ok());
+
+        let truncated = &serialized_ecs[..serialized_ecs.len() / 2];
+        assert!(deserialize_ecs(&mut new_world().ecs, truncated).is_err());
+    }
+
+    #[test]
+    fn malformed_deltas_are_rejected() {
+        let mut client = new_world();
+        let bad_components: Vec<EntitySnapshot> = vec![
+            // A component we've never heard of
+            vec![(999, serde_json::json!({}))].into_iter().collect(),
+            // A Location that isn't one
+            vec![(2, serde_json::json!("somewhere"))]
+                .into_iter()
+                .collect(),
+        ];
+        for components in bad_components {
+            let save_state = OptimisticGameSave {
+                delta: SnapshotDelta {
+                    tick: 1,
+                    changed: vec![(0, components)].into_iter().collect(),
+                    ..SnapshotDelta::default()
+                },
+                ..OptimisticGameSave::default()
+            };
+            assert!(load_game(&mut client.ecs, save_state).is_err());
+            assert_eq!(client.ecs.fetch::<AppliedSnapshot>().tick, None);
+        }
+    }
+
+    #[test]
+    fn compressed_message_pack_is_smaller() {
+        let server = new_world();
+        let snapshot = server.get_snapshot(server.current_tick());
+        let message = ServerMessage::SaveState(OptimisticGameSave {
+            delta: diff_snapshots(&[], &snapshot),
+            maybe_map: Some((*server.ecs.fetch::<Map>()).clone()),
+            last_input_sequence: None,
+        });
+        let plain = encode_server_message(&message, WireFormat::MessagePack).unwrap();
+        let compressed =
+            encode_server_message(&message, WireFormat::CompressedMessagePack).unwrap();
+        assert!(compressed.len() < plain.len());
+    }
+
+    /// Components of a single entity, with a number standing in for each value
+    fn components(values: &[(ComponentId, u32)]) -> EntitySnapshot {
+        values
+            .iter()
+            .map(|(component_id, value)| (*component_id, serde_json::json!(value)))
+            .collect()
+    }
+
+    fn snapshot(tick: u64, entities: &[(u64, &[(ComponentId, u32)])]) -> WorldSnapshot {
+        WorldSnapshot {
+            tick,
+            entities: entities
+                .iter()
+                .map(|(id, values)| (*id, components(values)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn deltas_bring_every_unacked_base_up_to_date() {
+        let older = snapshot(
+            1,
+            &[
+                (1, &[(2, 1)]),
+                (2, &[(2, 5)]),
+                (3, &[(2, 7)]),
+                (4, &[(2, 1), (3, 1)]),
+            ],
+        );
+        let newer = snapshot(2, &[(1, &[(2, 2)]), (2, &[(2, 5)]), (4, &[(2, 1), (3, 1)])]);
+        let target = snapshot(
+            3,
+            &[
+                (1, &[(2, 2)]),
+                (2, &[(2, 5)]),
+                (4, &[(2, 1)]),
+                (5, &[(2, 9)]),
+            ],
+        );
+
+        let delta = diff_snapshots(&[&older, &newer], &target);
+        assert_eq!(delta.base_tick, Some(1));
+        assert_eq!(delta.tick, 3);
+        // 1 moved since the older snapshot, even though the newer one already has it
+        // right. 2 is the same in both, so it's left out. 5 is new.
+        assert_eq!(
+            delta.changed,
+            vec![(1, components(&[(2, 2)])), (5, components(&[(2, 9)]))]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            delta.removed_components,
+            vec![(4, vec![3])].into_iter().collect()
+        );
+        // Only the older snapshot still has 3
+        assert_eq!(delta.deleted, vec![3].into_iter().collect());
+    }
+
+    #[test]
+    fn entities_enter_and_leave_the_area_of_interest() {
+        let world = snapshot(2, &[(1, &[(2, 1)]), (2, &[(2, 5)]), (3, &[(2, 7), (3, 1)])]);
+        // The client could see 1, 2 and 4. Now 2 has wandered off, 3 has come close and
+        // 4 is gone for good.
+        let base = snapshot(1, &[(1, &[(2, 1)]), (2, &[(2, 5)]), (4, &[(2, 3)])]);
+        let interest = vec![1, 3].into_iter().collect();
+
+        let mut delta = diff_snapshots(&[&base], &world.filtered(&interest));
+        delta.separate_left(&world);
+        assert_eq!(delta.entered, vec![3].into_iter().collect());
+        // Everything about an entity that's come into view is sent
+        assert_eq!(
+            delta.changed,
+            vec![(3, components(&[(2, 7), (3, 1)]))]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(delta.left, vec![2].into_iter().collect());
+        assert_eq!(delta.deleted, vec![4].into_iter().collect());
+    }
 }
@@ -0,0 +1,129 @@
+use specs::prelude::*;
+use std::time::Duration;
+
+use crate::animation::{AnimationSystem, DisappearingSystem};
+use crate::carry::{CarrySystem, PickUpSystem};
+use crate::crab_ai::CrabAISystem;
+use crate::effects::EffectsSystem;
+use crate::health::RespawnSystem;
+use crate::movement::{MovementSystem, TerrainSystem};
+use crate::occupancy::OccupancySystem;
+use crate::pathfinding::PathfindingSystem;
+use crate::spatial::SpatialIndexSystem;
+use crate::spawner::ItemSpawnerSystem;
+use crate::weapons::StabSystem;
+
+/// How long a tick lasts unless the server is configured otherwise
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most ticks we'll run back to back to catch up. Past this we drop the time
+/// on the floor rather than spiralling further behind.
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+/// Simulation time, readable by any system
+pub struct Time {
+    /// Number of ticks simulated so far
+    pub tick: u64,
+    /// Simulated time that passes each tick
+    pub delta: Duration,
+}
+
+impl Default for Time {
+    fn default() -> Time {
+        Time {
+            tick: 0,
+            delta: DEFAULT_TICK_INTERVAL,
+        }
+    }
+}
+
+/// Build the dispatcher which runs all our systems each tick. Systems only
+/// wait on the ones they depend on, so independent ones can run in parallel.
+pub fn build_dispatcher() -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with(OccupancySystem {}, "occupancy", &[])
+        .with(PathfindingSystem::default(), "pathfinding", &["occupancy"])
+        .with(MovementSystem::default(), "movement", &["pathfinding"])
+        .with(TerrainSystem {}, "terrain", &["movement"])
+        .with(DisappearingSystem {}, "disappearing", &[])
+        .with(CrabAISystem {}, "crab_ai", &["movement"])
+        .with(SpatialIndexSystem {}, "spatial_index", &["movement"])
+        // PickUp before Carry so we can update location
+        .with(PickUpSystem {}, "pick_up", &["spatial_index"])
+        .with(CarrySystem {}, "carry", &["pick_up"])
+        .with(StabSystem {}, "stab", &["carry"])
+        .with(RespawnSystem {}, "respawn", &["stab"])
+        // Shares the world rng with respawn, so always runs after it to stay reproducible
+        .with(ItemSpawnerSystem {}, "item_spawner", &["respawn"])
+        // Effects spawned lazily, so they show up once the tick is maintained
+        .with(EffectsSystem::default(), "effects", &["respawn"])
+        .with(AnimationSystem {}, "animation", &["stab"])
+        .build()
+}
+
+/// Works out how many ticks to run to keep the simulation at a fixed rate,
+/// no matter how long each tick (or the wait between them) actually took.
+pub struct FixedTimestep {
+    tick_interval: Duration,
+    accumulated: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_interval: Duration) -> FixedTimestep {
+        FixedTimestep {
+            tick_interval,
+            accumulated: Duration::from_secs(0),
+        }
+    }
+
+    /// Let `elapsed` real time pass and return the number of ticks now due
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.accumulated += elapsed;
+        let mut ticks = 0;
+        while self.accumulated >= self.tick_interval {
+            self.accumulated -= self.tick_interval;
+            ticks += 1;
+        }
+        if ticks > MAX_CATCH_UP_TICKS {
+            ticks = MAX_CATCH_UP_TICKS;
+        }
+        ticks
+    }
+
+    /// How long until the next tick is due
+    pub fn time_until_next_tick(&self) -> Duration {
+        self.tick_interval - self.accumulated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn time_accumulates_until_a_tick_is_due() {
+        let mut timestep = FixedTimestep::new(millis(100));
+        assert_eq!(timestep.advance(millis(40)), 0);
+        assert_eq!(timestep.advance(millis(40)), 0);
+        assert_eq!(timestep.time_until_next_tick(), millis(20));
+        assert_eq!(timestep.advance(millis(40)), 1);
+        // What's left over counts towards the next tick
+        assert_eq!(timestep.time_until_next_tick(), millis(80));
+        assert_eq!(timestep.advance(millis(230)), 2);
+        assert_eq!(timestep.time_until_next_tick(), millis(50));
+    }
+
+    #[test]
+    fn falling_far_behind_only_catches_up_so_much() {
+        let mut timestep = FixedTimestep::new(millis(100));
+        assert_eq!(timestep.advance(millis(2_030)), MAX_CATCH_UP_TICKS);
+        // The rest of the ticks are dropped, but not the part of one that's left over
+        assert_eq!(timestep.time_until_next_tick(), millis(70));
+        assert_eq!(timestep.advance(millis(0)), 0);
+        assert_eq!(timestep.advance(millis(70)), 1);
+    }
+}
@@ -0,0 +1,274 @@
+use oorandom::Rand32;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::iter;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ferris_chat::map::{locations_of_tile, Map, TileType};
+use ferris_chat::prefabs::Prefabs;
+use ferris_chat::state::WorldConfig;
+
+const USAGE: &str = "Usage: server [OPTIONS]
+
+Options are applied on top of the config file, if any.
+    --config <path>      JSON config file to load first
+    --bind <addr:port>   Address to listen on (default 0.0.0.0:3012)
+    --tick-rate <n>      Game ticks per second (default 10)
+    --send-rate <n>      Snapshots sent to each client per second (default 10)
+    --interest-radius <n>
+                         Tiles around their crab each client is sent entities for (default 50)
+    --reconnect-grace <n>
+                         Seconds a disconnected player's crab waits for them (default 30)
+    --width <n>          Map width in tiles (default 100)
+    --height <n>         Map height in tiles (default 100)
+    --seed <n>           Random seed for the map and crabs (default 1)
+    --ai-crabs <n>       Number of AI crabs (default 2)
+    --trees <n>          Number of trees (default 20)
+    --prefabs <path>     JSON file of prefabs to build entities from (default built in)
+    --save-dir <path>    Directory the world is saved to (default save)
+    --save-interval <n>  Seconds between saves, or 0 to only save on shutdown (default 60)
+    --load               Carry on from the world in the save directory
+    --item-spawns <n>    How many of each item to keep on the map (default 1)
+    --item-respawn-ticks <n>
+                         Ticks before a missing item is replaced (default 100)
+    --help               Print this message";
+
+const MIN_MAP_SIZE: i32 = 20;
+const MAX_MAP_SIZE: i32 = 1000;
+const MAX_TICK_RATE: u32 = 120;
+const MIN_INTEREST_RADIUS: f64 = 5.0;
+
+/// Everything the server binary can be configured with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind_address: SocketAddr,
+    /// Game ticks per second
+    pub tick_rate: u32,
+    /// Snapshots sent to each client per second
+    pub send_rate: u32,
+    /// Tiles around their crab each client is sent entities for
+    pub interest_radius: f64,
+    /// Seconds a disconnected player's crab stays in the world for them to reconnect to
+    pub reconnect_grace: u64,
+    /// Prefabs file to use instead of the built in one
+    pub prefabs: Option<String>,
+    pub save_dir: PathBuf,
+    /// Seconds between saves. Zero only saves on shutdown.
+    pub save_interval: u64,
+    /// Restore the world from `save_dir` rather than generating a new one
+    pub load: bool,
+    pub world: WorldConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind_address: "0.0.0.0:3012".parse().unwrap(),
+            tick_rate: 10,
+            send_rate: 10,
+            interest_radius: 50.0,
+            reconnect_grace: 30,
+            prefabs: None,
+            save_dir: PathBuf::from("save"),
+            save_interval: 60,
+            load: false,
+            world: WorldConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.tick_rate
+    }
+
+    pub fn send_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.send_rate
+    }
+
+    /// How long a disconnected player has to reconnect before their crab is removed
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.reconnect_grace)
+    }
+
+    /// How often to save the world, if we're saving it periodically at all
+    pub fn save_interval(&self) -> Option<Duration> {
+        match self.save_interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Make sure we can actually run with this config and these prefabs
+    pub fn validate(&self, prefabs: &Prefabs) -> Result<(), String> {
+        if self.tick_rate == 0 || self.tick_rate > MAX_TICK_RATE {
+            return Err(format!("tick rate must be between 1 and {}", MAX_TICK_RATE));
+        }
+        // Sending faster than we tick would just send the same snapshot twice
+        if self.send_rate == 0 || self.send_rate > self.tick_rate {
+            return Err(format!(
+                "send rate must be between 1 and the tick rate ({})",
+                self.tick_rate
+            ));
+        }
+        // Any smaller and players wouldn't even see what they can reach
+        if !(self.interest_radius >= MIN_INTEREST_RADIUS) {
+            return Err(format!(
+                "interest radius must be at least {}",
+                MIN_INTEREST_RADIUS
+            ));
+        }
+        let world = &self.world;
+        for (name, size) in &[("width", world.width), ("height", world.height)] {
+            if *size < MIN_MAP_SIZE || *size > MAX_MAP_SIZE {
+                return Err(format!(
+                    "map {} must be between {} and {}",
+                    name, MIN_MAP_SIZE, MAX_MAP_SIZE
+                ));
+            }
+        }
+        // Nothing looks for an empty tile to spawn on, so keep the map from being buried
+        let max_spawns = (world.width * world.height / 10) as u32;
+        let spawns = world.ai_crabs + world.trees + world.items.total();
+        if spawns > max_spawns {
+            return Err(format!(
+                "{} crabs, trees and items won't fit on a {}x{} map (at most {})",
+                spawns, world.width, world.height, max_spawns
+            ));
+        }
+        // A loaded world keeps the map it was saved with
+        if self.load {
+            return Ok(());
+        }
+        self.validate_spawns(prefabs)
+    }
+
+    /// Generate the map the world starts with and make sure there's somewhere on it for
+    /// everything to spawn
+    fn validate_spawns(&self, prefabs: &Prefabs) -> Result<(), String> {
+        let world = &self.world;
+        let map = Map::new(&mut Rand32::new(world.seed), world.width, world.height);
+        let map_name = format!(
+            "the {}x{} map from seed {}",
+            map.width, map.height, world.seed
+        );
+        if locations_of_tile(&map, Some(TileType::Sand)).is_empty() {
+            return Err(format!("{} has no beach for players to spawn on", map_name));
+        }
+        if world.ai_crabs > 0 && locations_of_tile(&map, Some(TileType::Grass)).is_empty() {
+            return Err(format!(
+                "{} has no grass for AI crabs to spawn on",
+                map_name
+            ));
+        }
+
+        // Add up everything that spawns on each kind of tile
+        let mut wanted: Vec<(Option<TileType>, u32)> = Vec::new();
+        let items = world.items.targets.iter();
+        for (name, count) in iter::once(("tree", world.trees))
+            .chain(items.map(|(name, target)| (name.as_str(), *target)))
+        {
+            let spawn_tile = prefabs.get(name).and_then(|prefab| prefab.spawn_tile);
+            match wanted
+                .iter_mut()
+                .find(|(tile_type, _)| *tile_type == spawn_tile)
+            {
+                Some((_, total)) => *total += count,
+                None => wanted.push((spawn_tile, count)),
+            }
+        }
+        for (spawn_tile, count) in wanted {
+            let tiles = locations_of_tile(&map, spawn_tile).len();
+            if count as usize > tiles {
+                let tile_name = match spawn_tile {
+                    Some(tile_type) => format!("{:?}", tile_type),
+                    None => String::from("land"),
+                };
+                return Err(format!(
+                    "{} trees and items spawn on {} but {} only has {} of it",
+                    count, tile_name, map_name, tiles
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the prefabs and make sure they have everything the world needs
+    pub fn load_prefabs(&self) -> Result<Prefabs, String> {
+        let prefabs = match &self.prefabs {
+            Some(path) => Prefabs::load_file(path)?,
+            None => Prefabs::default(),
+        };
+        prefabs.validate_items(&self.world.items)?;
+        Ok(prefabs)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+fn load_config_file(path: &str) -> Result<ServerConfig, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("can't read config {}: {}", path, e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("invalid config {}: {}", path, e))
+}
+
+/// Build the config from the command line arguments (without the program name).
+/// Returns Ok(None) if the user just asked for help. The config still has to be
+/// validated against the prefabs it loads.
+pub fn parse_args(args: Vec<String>) -> Result<Option<ServerConfig>, String> {
+    // The config file is the base, so find it before applying anything else
+    let mut config = match args.iter().position(|arg| arg == "--config") {
+        Some(index) => load_config_file(
+            args.get(index + 1)
+                .ok_or_else(|| String::from("--config needs a value"))?,
+        )?,
+        None => ServerConfig::default(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            "--config" => {
+                args.next();
+            }
+            "--bind" => config.bind_address = parse_value(&flag, args.next())?,
+            "--tick-rate" => config.tick_rate = parse_value(&flag, args.next())?,
+            "--send-rate" => config.send_rate = parse_value(&flag, args.next())?,
+            "--interest-radius" => config.interest_radius = parse_value(&flag, args.next())?,
+            "--reconnect-grace" => config.reconnect_grace = parse_value(&flag, args.next())?,
+            "--width" => config.world.width = parse_value(&flag, args.next())?,
+            "--height" => config.world.height = parse_value(&flag, args.next())?,
+            "--seed" => config.world.seed = parse_value(&flag, args.next())?,
+            "--ai-crabs" => config.world.ai_crabs = parse_value(&flag, args.next())?,
+            "--trees" => config.world.trees = parse_value(&flag, args.next())?,
+            "--prefabs" => config.prefabs = Some(parse_value(&flag, args.next())?),
+            "--save-dir" => config.save_dir = parse_value(&flag, args.next())?,
+            "--save-interval" => config.save_interval = parse_value(&flag, args.next())?,
+            "--load" => config.load = true,
+            "--item-spawns" => {
+                let count = parse_value(&flag, args.next())?;
+                for target in config.world.items.targets.values_mut() {
+                    *target = count;
+                }
+            }
+            "--item-respawn-ticks" => {
+                config.world.items.respawn_ticks = parse_value(&flag, args.next())?
+            }
+            _ => return Err(format!("unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    Ok(Some(config))
+}
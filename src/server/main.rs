@@ -1,80 +1,347 @@
 extern crate serde;
 
 use specs::prelude::*;
-use std::sync::{Arc, Mutex};
-use std::{thread, time};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
 
-use ferris_chat::saveload_system::{deserialize_player_input, package_save_state};
+use ferris_chat::components::{PlayerInfo, TextRenderable};
+use ferris_chat::entities::set_player_idle;
+use ferris_chat::events::{GameEvent, GameEvents};
+use ferris_chat::interest::entities_of_interest;
+use ferris_chat::map::Map;
+use ferris_chat::prefabs::Prefabs;
+use ferris_chat::saveload_system::{PlayerInput, ServerMessage, COMPONENT_REGISTRY_VERSION};
+use ferris_chat::scheduler::{FixedTimestep, Time};
 use ferris_chat::state::{initialize_ecs, State};
+mod config;
+mod persistence;
 mod websocket_server;
-use websocket_server::{start_async_server, AsyncStatePtr};
+use config::{parse_args, ServerConfig};
+use persistence::{load_world, save_world};
+use websocket_server::{start_async_server, ClientView, ConnectionId, EngineEvent, PublishedState};
 
-fn start_game_engine(
-    shared_full_state: AsyncStatePtr,
-    shared_incr_state: AsyncStatePtr,
-    shared_input_queue: AsyncStatePtr,
+/// A connected client as far as the engine is concerned
+struct Client {
+    player_id: String,
+    sender: UnboundedSender<ServerMessage>,
+    /// Echoed back in snapshots so the client can tell which of its clicks we've seen
+    last_input_sequence: Option<u32>,
+    /// Lets whoever holds it take this player back from another connection
+    resume_token: String,
+}
+
+impl Client {
+    /// Send a message to this client only. If they've gone away the
+    /// Disconnected event is already on its way, so there's nothing to do.
+    fn send(&self, message: ServerMessage) {
+        let _ = self.sender.send(message);
+    }
+
+    fn welcome(&self, gs: &State) {
+        self.send(ServerMessage::Welcome {
+            player_id: self.player_id.clone(),
+            resume_token: Some(self.resume_token.clone()),
+            component_registry_version: COMPONENT_REGISTRY_VERSION,
+            tick_interval_ms: gs.ecs.fetch::<Time>().delta.as_secs_f64() * 1000.0,
+        });
+    }
+}
+
+/// A player whose connection dropped, and whose crab is waiting for them to come back
+struct IdlePlayer {
+    player_id: String,
+    since: Instant,
+}
+
+/// Every connection gets a new player id, so clients can never act as someone else
+fn player_id_for_connection(connection_id: ConnectionId) -> String {
+    format!("player-{}", connection_id)
+}
+
+/// Token that's hard enough to guess that nobody else can take over a player's crab:
+/// 128 bits from the OS's secure random number generator
+fn new_resume_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("The OS couldn't give us any random numbers");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Give the connection back the player the token was issued for, if they're still around
+fn resume_session(
+    gs: &mut State,
+    clients: &mut HashMap<ConnectionId, Client>,
+    idle_players: &mut HashMap<String, IdlePlayer>,
+    connection_id: ConnectionId,
+    token: &str,
 ) {
-    let width: i32 = 100;
-    let height: i32 = 100;
+    // The old connection may not have noticed it's gone yet, so look there too
+    let resumed_player_id = match idle_players.remove(token) {
+        Some(idle_player) => Some(idle_player.player_id),
+        None => clients
+            .iter()
+            .find(|(id, client)| **id != connection_id && client.resume_token == token)
+            .map(|(id, _)| *id)
+            .and_then(|stale_id| clients.remove(&stale_id))
+            .map(|stale| stale.player_id),
+    };
+    let client = match clients.get_mut(&connection_id) {
+        Some(client) => client,
+        None => return,
+    };
+    let player_id = match resumed_player_id {
+        Some(player_id) => player_id,
+        None => {
+            println!("{} has no session to resume", client.player_id);
+            return;
+        }
+    };
+    println!("{} resumed as {}", client.player_id, player_id);
+    // Anything this connection made for itself is given up for the old crab
+    gs.handle_player_input(&client.player_id, PlayerInput::DeletePlayer);
+    set_player_idle(&mut gs.ecs, &player_id, false);
+    client.player_id = player_id;
+    client.resume_token = new_resume_token();
+    client.welcome(gs);
+}
+
+/// Remove the crabs of players who've been gone longer than the grace period
+fn expire_idle_players(
+    gs: &mut State,
+    idle_players: &mut HashMap<String, IdlePlayer>,
+    grace: Duration,
+) {
+    let now = Instant::now();
+    idle_players.retain(|_, idle_player| {
+        if now - idle_player.since < grace {
+            return true;
+        }
+        println!("{} didn't reconnect in time", idle_player.player_id);
+        gs.handle_player_input(&idle_player.player_id, PlayerInput::DeletePlayer);
+        false
+    });
+}
+
+fn handle_engine_event(
+    gs: &mut State,
+    clients: &mut HashMap<ConnectionId, Client>,
+    idle_players: &mut HashMap<String, IdlePlayer>,
+    event: EngineEvent,
+) {
+    match event {
+        EngineEvent::Connected {
+            connection_id,
+            sender,
+        } => {
+            let player_id = player_id_for_connection(connection_id);
+            let client = Client {
+                player_id,
+                sender,
+                last_input_sequence: None,
+                resume_token: new_resume_token(),
+            };
+            // Tell the client which crab is theirs before anything else
+            client.welcome(gs);
+            clients.insert(connection_id, client);
+        }
+        EngineEvent::Input {
+            connection_id,
+            input,
+        } => {
+            if let PlayerInput::Resume { token } = &input {
+                resume_session(gs, clients, idle_players, connection_id, token);
+                return;
+            }
+            if let Some(client) = clients.get_mut(&connection_id) {
+                println!("Received input from {}: {:?}", client.player_id, input);
+                if let PlayerInput::Click { sequence, .. } = input {
+                    client.last_input_sequence = Some(sequence);
+                }
+                gs.handle_player_input(&client.player_id, input);
+            }
+        }
+        EngineEvent::Disconnected { connection_id } => {
+            // Their crab waits for them to reconnect, until expire_idle_players gives up
+            if let Some(client) = clients.remove(&connection_id) {
+                set_player_idle(&mut gs.ecs, &client.player_id, true);
+                idle_players.insert(
+                    client.resume_token,
+                    IdlePlayer {
+                        player_id: client.player_id,
+                        since: Instant::now(),
+                    },
+                );
+            }
+        }
+        // The engine loop stops itself, since it's the one that has to save
+        EngineEvent::Shutdown => {}
+    }
+}
+
+fn save(gs: &mut State, config: &ServerConfig) {
+    match save_world(gs, &config.save_dir) {
+        Ok(()) => println!("Saved world to {}", config.save_dir.display()),
+        Err(error) => eprintln!(
+            "Failed to save world to {}: {}",
+            config.save_dir.display(),
+            error
+        ),
+    }
+}
+
+/// Player id and name of a crab, if it's still around
+fn describe_player(ecs: &World, entity: Entity) -> Option<(String, String)> {
+    let player_info = ecs.read_storage::<PlayerInfo>().get(entity)?.clone();
+    let name = ecs
+        .read_storage::<TextRenderable>()
+        .get(entity)
+        .map(|text_renderable| text_renderable.text.clone())
+        .unwrap_or_default();
+    Some((player_info.id, name))
+}
 
-    let mut gs = State { ecs: World::new() };
-    initialize_ecs(&mut gs.ecs, width, height, 1 as u64);
-    // Cache the serialized map because that never changes
-    let serialized_map = gs.get_serialized_map();
+/// Let the clients involved know about game events which concern them
+fn notify_clients(ecs: &World, clients: &HashMap<ConnectionId, Client>, event: &GameEvent) {
+    if let GameEvent::PlayerKilled { victim, killer, .. } = event {
+        let (victim_id, victim_name) = match describe_player(ecs, *victim) {
+            Some(victim) => victim,
+            None => return,
+        };
+        let (killer_id, killer_name) = describe_player(ecs, *killer).unwrap_or_default();
+        println!("{} was killed by {}", victim_id, killer_id);
+        for client in clients.values() {
+            if client.player_id == victim_id || client.player_id == killer_id {
+                client.send(ServerMessage::PlayerKilled {
+                    killer: killer_name.clone(),
+                    victim: victim_name.clone(),
+                });
+            }
+        }
+    }
+}
 
+fn start_game_engine(
+    config: &ServerConfig,
+    prefabs: Prefabs,
+    snapshot_sender: watch::Sender<Option<PublishedState>>,
+    mut engine_receiver: UnboundedReceiver<EngineEvent>,
+) {
+    let mut gs = State::new();
+    let mut event_reader = gs.ecs.fetch_mut::<GameEvents>().register_reader();
+    if config.load {
+        if let Err(error) = load_world(&mut gs, &config.save_dir, &config.world, prefabs) {
+            eprintln!("error: {}", error);
+            std::process::exit(2);
+        }
+        println!("Loaded world from {}", config.save_dir.display());
+    } else {
+        initialize_ecs(&mut gs.ecs, &config.world, prefabs);
+    }
+    gs.ecs.write_resource::<Time>().delta = config.tick_interval();
+    // Share a single copy of the map because that never changes
+    let map = Arc::new((*gs.ecs.fetch::<Map>()).clone());
+
+    let mut clients: HashMap<ConnectionId, Client> = HashMap::new();
+    let mut idle_players: HashMap<String, IdlePlayer> = HashMap::new();
+    let mut timestep = FixedTimestep::new(config.tick_interval());
+    let mut last_update = Instant::now();
+    let mut last_save = Instant::now();
     loop {
-        {
-            // Process the player input queue
-            let mut input_queue = shared_input_queue.lock().unwrap();
-            for player_input in input_queue.iter() {
-                println!("Received input: {:?}", player_input);
-                gs.handle_player_input(deserialize_player_input((&player_input).to_string()));
+        // Process everything the network sent us since last tick, in the order it arrived
+        while let Ok(event) = engine_receiver.try_recv() {
+            match event {
+                EngineEvent::Shutdown => {
+                    save(&mut gs, config);
+                    return;
+                }
+                event => handle_engine_event(&mut gs, &mut clients, &mut idle_players, event),
             }
-            input_queue.clear();
         }
+        expire_idle_players(&mut gs, &mut idle_players, config.reconnect_grace());
 
-        gs.tick();
-
-        // Serialize our ECS
-        let serialized_ecs = gs.get_serialized_ecs();
-
-        {
-            // Save a copy of the incremental state for existing clients
-            let mut incr_state_mut = shared_incr_state.lock().unwrap();
-            incr_state_mut.clear();
-            incr_state_mut.push(package_save_state(serialized_ecs.clone(), None));
-            // Save a copy of the full state for new clients
-            let mut full_state_mut = shared_full_state.lock().unwrap();
-            full_state_mut.clear();
-            full_state_mut.push(package_save_state(
-                serialized_ecs,
-                Some(serialized_map.clone()),
-            ));
+        // Run as many ticks as real time says we're due, however long the last ones took
+        let now = Instant::now();
+        let ticks_due = timestep.advance(now - last_update);
+        last_update = now;
+        for _ in 0..ticks_due {
+            gs.tick();
+            for event in gs.ecs.fetch::<GameEvents>().read(&mut event_reader) {
+                notify_clients(&gs.ecs, &clients, event);
+            }
+        }
+
+        if let Some(save_interval) = config.save_interval() {
+            if now - last_save >= save_interval {
+                save(&mut gs, config);
+                last_save = now;
+            }
         }
 
-        // println!("tick");
-        thread::sleep(time::Duration::from_millis(100));
+        if ticks_due > 0 {
+            // Publish a snapshot of our ECS for the clients to diff against
+            let views = clients
+                .iter()
+                .map(|(connection_id, client)| {
+                    let view = ClientView {
+                        interest: entities_of_interest(
+                            &gs.ecs,
+                            &client.player_id,
+                            config.interest_radius,
+                        ),
+                        last_input_sequence: client.last_input_sequence,
+                    };
+                    (*connection_id, view)
+                })
+                .collect();
+            let _ = snapshot_sender.broadcast(Some(PublishedState {
+                snapshot: Arc::new(gs.get_snapshot(gs.current_tick())),
+                map: map.clone(),
+                clients: Arc::new(views),
+            }));
+        }
+
+        thread::sleep(timestep.time_until_next_tick());
     }
 }
 
 fn main() {
-    // This is my shitty way to sync save data between threads. I'm new to rust,
-    // so I have no idea what I'm doing and if this is bad.
-    let shared_full_state = Arc::new(Mutex::new(Vec::with_capacity(1)));
-    let shared_incr_state = Arc::new(Mutex::new(Vec::with_capacity(1)));
-    let shared_input_queue = Arc::new(Mutex::new(Vec::new()));
+    let config = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(Some(config)) => config,
+        Ok(None) => return,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(2);
+        }
+    };
+    // Check the prefabs and config now rather than finding out they're broken when
+    // something spawns
+    let prefabs = match config
+        .load_prefabs()
+        .and_then(|prefabs| config.validate(&prefabs).map(|_| prefabs))
+    {
+        Ok(prefabs) => prefabs,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(2);
+        }
+    };
+
+    // The engine publishes snapshots for every connection to read, and the
+    // connections send their events back to the engine in order.
+    let (snapshot_sender, snapshot_receiver) = watch::channel(None);
+    let (engine_sender, engine_receiver) = unbounded_channel();
 
     // Start listening for client connections in a new thread
     start_async_server(
-        shared_full_state.clone(),
-        shared_incr_state.clone(),
-        shared_input_queue.clone(),
+        config.bind_address,
+        config.send_interval(),
+        snapshot_receiver,
+        engine_sender,
     );
 
     // Block while running the game engine
-    start_game_engine(
-        shared_full_state.clone(),
-        shared_incr_state.clone(),
-        shared_input_queue.clone(),
-    );
+    start_game_engine(&config, prefabs, snapshot_sender, engine_receiver);
 }
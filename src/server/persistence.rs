@@ -0,0 +1,187 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ferris_chat::map::Map;
+use ferris_chat::prefabs::Prefabs;
+use ferris_chat::saveload_system::COMPONENT_REGISTRY_VERSION;
+use ferris_chat::state::{restore_ecs, SaveMetadata, State, WorldConfig, SAVE_FORMAT_VERSION};
+
+const MAP_FILE: &str = "map.json";
+const ECS_FILE: &str = "ecs.json";
+const METADATA_FILE: &str = "metadata.json";
+
+/// Directory next to `save_dir` with `suffix` added to its name
+fn sibling_dir(save_dir: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let name = save_dir.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} doesn't name a directory", save_dir.display()),
+        )
+    })?;
+    let mut sibling_name = name.to_os_string();
+    sibling_name.push(suffix);
+    Ok(save_dir.with_file_name(sibling_name))
+}
+
+fn remove_dir_if_present(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))
+}
+
+/// Save the map, every marked entity and enough bookkeeping to carry on from here.
+/// Everything is written to a new directory which then replaces `save_dir`, so a crash
+/// halfway through saving leaves the last complete save where it was.
+pub fn save_world(gs: &mut State, save_dir: &Path) -> io::Result<()> {
+    let new_dir = sibling_dir(save_dir, ".tmp")?;
+    let old_dir = sibling_dir(save_dir, ".old")?;
+    remove_dir_if_present(&new_dir)?;
+    fs::create_dir_all(&new_dir)?;
+    let metadata = serde_json::to_string(&gs.get_save_metadata())?;
+    fs::write(new_dir.join(MAP_FILE), gs.get_serialized_map())?;
+    fs::write(new_dir.join(ECS_FILE), gs.get_serialized_ecs())?;
+    fs::write(new_dir.join(METADATA_FILE), metadata)?;
+
+    // A directory can't be renamed over one with files in it, so move the last save out
+    // of the way first. load_world falls back to it if we don't get any further.
+    remove_dir_if_present(&old_dir)?;
+    if save_dir.exists() {
+        fs::rename(save_dir, &old_dir)?;
+    }
+    fs::rename(&new_dir, save_dir)?;
+    remove_dir_if_present(&old_dir)
+}
+
+/// Restore the world saved in `save_dir` by `save_world`
+pub fn load_world(
+    gs: &mut State,
+    save_dir: &Path,
+    config: &WorldConfig,
+    prefabs: Prefabs,
+) -> Result<(), String> {
+    let old_dir = sibling_dir(save_dir, ".old").map_err(|e| e.to_string())?;
+    let save_dir = if !save_dir.exists() && old_dir.exists() {
+        // We stopped in the middle of replacing the last save
+        old_dir.as_path()
+    } else {
+        save_dir
+    };
+
+    let metadata: SaveMetadata = serde_json::from_str(&read_file(&save_dir.join(METADATA_FILE))?)
+        .map_err(|e| format!("invalid save metadata: {}", e))?;
+    if metadata.format_version != SAVE_FORMAT_VERSION {
+        return Err(format!(
+            "save format version {} isn't supported, expected version {}",
+            metadata.format_version, SAVE_FORMAT_VERSION
+        ));
+    }
+    if metadata.component_registry_version != COMPONENT_REGISTRY_VERSION {
+        return Err(format!(
+            "save has component registry version {}, expected version {}",
+            metadata.component_registry_version, COMPONENT_REGISTRY_VERSION
+        ));
+    }
+    let map: Map = serde_json::from_str(&read_file(&save_dir.join(MAP_FILE))?)
+        .map_err(|e| format!("invalid saved map: {}", e))?;
+    let serialized_ecs = read_file(&save_dir.join(ECS_FILE))?;
+    restore_ecs(
+        &mut gs.ecs,
+        config,
+        prefabs,
+        map,
+        &serialized_ecs,
+        &metadata,
+    )
+    .map_err(|e| format!("invalid saved entities: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ferris_chat::components::{CarriedBy, ChatRenderable, CrabAI, EntityMarker, PlayerInfo};
+    use ferris_chat::entities::{create_chat_bubble, get_player_with_id, spawn_crab};
+    use ferris_chat::state::initialize_ecs;
+    use specs::prelude::*;
+    use specs::saveload::{MarkedBuilder, Marker, SimpleMarker};
+    use std::collections::BTreeSet;
+
+    /// Marker ids of every entity in `ecs`
+    fn marker_ids(ecs: &World) -> BTreeSet<u64> {
+        ecs.read_storage::<SimpleMarker<EntityMarker>>()
+            .join()
+            .map(|marker| marker.id())
+            .collect()
+    }
+
+    #[test]
+    fn saved_worlds_load_back_without_their_players() {
+        let config = WorldConfig::default();
+        let mut server = State::new();
+        initialize_ecs(&mut server.ecs, &config, Prefabs::default());
+        spawn_crab(&mut server.ecs, "player-0", "Ferris", false);
+        let player = get_player_with_id(&server.ecs, &String::from("player-0")).unwrap();
+        create_chat_bubble(&mut server.ecs, String::from("Hello"), player);
+        for _ in 0..10 {
+            server.tick();
+        }
+
+        let save_dir =
+            std::env::temp_dir().join(format!("ferris_chat_save_{}", std::process::id()));
+        save_world(&mut server, &save_dir).expect("Failed to save");
+        let mut loaded = State::new();
+        let result = load_world(&mut loaded, &save_dir, &config, Prefabs::default());
+        fs::remove_dir_all(&save_dir).unwrap();
+        result.expect("Failed to load");
+
+        // Everything comes back as it was, apart from the player's crab and everything
+        // that went around with it
+        let mut expected = server.get_snapshot(server.current_tick());
+        {
+            let markers = server.ecs.read_storage::<SimpleMarker<EntityMarker>>();
+            let carried_bys = server.ecs.read_storage::<CarriedBy>();
+            expected.entities.remove(&markers.get(player).unwrap().id());
+            for (marker, carried_by) in (&markers, &carried_bys).join() {
+                if carried_by.owner == player {
+                    expected.entities.remove(&marker.id());
+                }
+            }
+        }
+        assert_eq!(loaded.current_tick(), server.current_tick());
+        assert_eq!(
+            loaded.get_snapshot(loaded.current_tick()).entities,
+            expected.entities
+        );
+        let players = (
+            &loaded.ecs.read_storage::<PlayerInfo>(),
+            !&loaded.ecs.read_storage::<CrabAI>(),
+        )
+            .join()
+            .count();
+        assert_eq!(players, 0);
+        assert_eq!(
+            loaded.ecs.read_storage::<ChatRenderable>().join().count(),
+            0
+        );
+
+        // New entities carry on numbering from the saved ones rather than reusing an id
+        let restored_ids = marker_ids(&loaded.ecs);
+        let new_entity = loaded
+            .ecs
+            .create_entity()
+            .marked::<SimpleMarker<EntityMarker>>()
+            .build();
+        let new_id = loaded
+            .ecs
+            .read_storage::<SimpleMarker<EntityMarker>>()
+            .get(new_entity)
+            .unwrap()
+            .id();
+        assert!(restored_ids.iter().all(|id| *id < new_id));
+    }
+}
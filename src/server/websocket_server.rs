@@ -1,97 +1,400 @@
-use futures_util::future::{select, Either};
 use futures_util::{SinkExt, StreamExt};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::accept_async;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::watch;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::HeaderValue;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
 use tungstenite::{Message, Result};
 
-use ferris_chat::saveload_system::{deserialize_player_input, serialize_player_input, PlayerInput};
+use ferris_chat::map::Map;
+use ferris_chat::saveload_system::{
+    deserialize_player_input, diff_snapshots, encode_server_message, package_save_state,
+    EncodeError, PlayerInput, ProtocolError, ServerMessage, WireFormat, WorldSnapshot,
+    MAX_PLAYER_INPUT_BYTES,
+};
 
-pub type AsyncStatePtr = Arc<Mutex<Vec<String>>>;
+/// Identifies a single WebSocket connection for the lifetime of the server
+pub type ConnectionId = u64;
+
+/// Everything the network side tells the game engine, in the order it happened
+pub enum EngineEvent {
+    /// A client connected. The engine can send it messages through `sender`.
+    Connected {
+        connection_id: ConnectionId,
+        sender: UnboundedSender<ServerMessage>,
+    },
+    Input {
+        connection_id: ConnectionId,
+        input: PlayerInput,
+    },
+    Disconnected {
+        connection_id: ConnectionId,
+    },
+    /// We've been asked to stop, so save and exit
+    Shutdown,
+}
+
+/// Latest world state published by the game engine for the connections to send out
+#[derive(Clone)]
+pub struct PublishedState {
+    pub snapshot: Arc<WorldSnapshot>,
+    pub map: Arc<Map>,
+    pub clients: Arc<HashMap<ConnectionId, ClientView>>,
+}
+
+/// The parts of a published state which differ from one connection to the next
+pub struct ClientView {
+    /// Marker ids of the entities this connection gets to see
+    pub interest: BTreeSet<u64>,
+    /// Sequence number of the last click from this connection the engine processed
+    pub last_input_sequence: Option<u32>,
+}
+
+/// Always holds the most recently published state (None until the first tick)
+pub type SnapshotReceiver = watch::Receiver<Option<PublishedState>>;
+
+/// If a client hasn't acknowledged anything in this many sends, start over with a full snapshot
+const MAX_UNACKED_SNAPSHOTS: usize = 50;
+
+/// Most send intervals we'll skip between full snapshots to a client which hasn't
+/// acknowledged any yet
+const MAX_FULL_SNAPSHOT_BACKOFF: u32 = 32;
+
+/// Number of bad messages we put up with before disconnecting a client
+const MAX_PROTOCOL_OFFENCES: u32 = 3;
+
+/// Tracks which snapshots a single client has been sent so we only send what changed
+struct ClientSnapshots {
+    acked_tick: Option<u64>,
+    /// Every snapshot sent since (and including) the acknowledged one
+    unacked: VecDeque<Arc<WorldSnapshot>>,
+    /// The map never changes, so it's only sent with the first full snapshot
+    map_sent: bool,
+    /// Send intervals to skip after the next full snapshot, doubling every time one
+    /// goes unacknowledged so a client that never acks isn't sent everything every time
+    full_snapshot_backoff: u32,
+    /// Send intervals left to skip before sending another full snapshot
+    intervals_until_full_snapshot: u32,
+}
+
+impl ClientSnapshots {
+    fn new() -> ClientSnapshots {
+        ClientSnapshots {
+            acked_tick: None,
+            unacked: VecDeque::new(),
+            map_sent: false,
+            full_snapshot_backoff: 1,
+            intervals_until_full_snapshot: 0,
+        }
+    }
+
+    fn acknowledge(&mut self, tick: u64) {
+        if let Some(index) = self.unacked.iter().position(|sent| sent.tick == tick) {
+            self.unacked.drain(..index);
+            self.acked_tick = Some(tick);
+            self.full_snapshot_backoff = 1;
+            self.intervals_until_full_snapshot = 0;
+        }
+    }
+
+    /// Package the delta between what the client may have and the part of the latest
+    /// snapshot in their area of interest.
+    /// Returns None if there's nothing to send yet.
+    fn package_next(
+        &mut self,
+        published: &PublishedState,
+        view: &ClientView,
+        format: WireFormat,
+    ) -> std::result::Result<Option<Vec<u8>>, EncodeError> {
+        if let Some(last_sent) = self.unacked.back() {
+            if last_sent.tick == published.snapshot.tick {
+                return Ok(None);
+            }
+        }
+        if self.acked_tick.is_none() {
+            if self.intervals_until_full_snapshot > 0 {
+                self.intervals_until_full_snapshot -= 1;
+                return Ok(None);
+            }
+            self.intervals_until_full_snapshot = self.full_snapshot_backoff;
+            self.full_snapshot_backoff =
+                (self.full_snapshot_backoff * 2).min(MAX_FULL_SNAPSHOT_BACKOFF);
+        }
+
+        let snapshot = Arc::new(published.snapshot.filtered(&view.interest));
+        let save_state = match self.acked_tick {
+            Some(_) => {
+                let bases: Vec<&WorldSnapshot> = self.unacked.iter().map(|sent| &**sent).collect();
+                let mut delta = diff_snapshots(&bases, &snapshot);
+                delta.separate_left(&published.snapshot);
+                package_save_state(delta, None, view.last_input_sequence, format)?
+            }
+            None => {
+                // Until they acknowledge something, send everything
+                let maybe_map = if self.map_sent {
+                    None
+                } else {
+                    self.map_sent = true;
+                    Some((*published.map).clone())
+                };
+                package_save_state(
+                    diff_snapshots(&[], &snapshot),
+                    maybe_map,
+                    view.last_input_sequence,
+                    format,
+                )?
+            }
+        };
+
+        self.unacked.push_back(snapshot);
+        if self.unacked.len() > MAX_UNACKED_SNAPSHOTS {
+            self.acked_tick = None;
+            self.unacked.clear();
+        }
+        Ok(Some(save_state))
+    }
+}
+
+/// Pick the first format the client asked for (via Sec-WebSocket-Protocol) that we support.
+/// Clients which don't ask for anything get JSON.
+fn negotiate_wire_format(request: &Request) -> Option<WireFormat> {
+    match request.headers().get("Sec-WebSocket-Protocol") {
+        Some(protocols) => protocols
+            .to_str()
+            .unwrap_or("")
+            .split(',')
+            .filter_map(WireFormat::from_protocol_name)
+            .next(),
+        None => Some(WireFormat::Json),
+    }
+}
+
+fn server_message(encoded: Vec<u8>, format: WireFormat) -> Message {
+    if format.is_binary() {
+        Message::Binary(encoded)
+    } else {
+        Message::Text(String::from_utf8(encoded).expect("JSON server message wasn't UTF-8"))
+    }
+}
+
+/// Tells the engine a connection has gone once it's dropped, so its player is cleaned up
+/// however the connection ends, even if handling it panics
+struct EngineRegistration {
+    connection_id: ConnectionId,
+    engine_sender: UnboundedSender<EngineEvent>,
+}
+
+impl Drop for EngineRegistration {
+    fn drop(&mut self) {
+        let _ = self.engine_sender.send(EngineEvent::Disconnected {
+            connection_id: self.connection_id,
+        });
+    }
+}
+
+fn next_connection_id() -> ConnectionId {
+    static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 async fn handle_connection(
     peer: SocketAddr,
     stream: TcpStream,
-    shared_full_state: AsyncStatePtr,
-    shared_incr_state: AsyncStatePtr,
-    shared_input_queue: AsyncStatePtr,
+    send_interval: Duration,
+    snapshot_receiver: SnapshotReceiver,
+    engine_sender: UnboundedSender<EngineEvent>,
 ) -> Result<()> {
-    let ws_stream = accept_async(stream).await.expect("Failed to accept");
-    println!("New WebSocket connection: {}", peer);
+    let mut wire_format = WireFormat::Json;
+    // Anything much bigger than an input is rejected by tungstenite before we buffer it
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_PLAYER_INPUT_BYTES * 4),
+        max_frame_size: Some(MAX_PLAYER_INPUT_BYTES * 4),
+        ..WebSocketConfig::default()
+    };
+    let ws_stream = accept_hdr_async_with_config(
+        stream,
+        |request: &Request,
+         mut response: Response|
+         -> std::result::Result<Response, ErrorResponse> {
+            if let Some(format) = negotiate_wire_format(request) {
+                if request.headers().contains_key("Sec-WebSocket-Protocol") {
+                    response.headers_mut().insert(
+                        "Sec-WebSocket-Protocol",
+                        HeaderValue::from_static(format.protocol_name()),
+                    );
+                }
+                wire_format = format;
+            }
+            Ok(response)
+        },
+        Some(config),
+    )
+    .await?;
+    println!("New WebSocket connection: {} ({:?})", peer, wire_format);
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    let mut interval = tokio::time::interval(send_interval);
 
-    let mut player_id: Option<String> = None;
+    // Register with the engine so it can assign us a player and talk to us directly
+    let connection_id = next_connection_id();
+    let (client_sender, mut client_receiver) = unbounded_channel();
+    let _ = engine_sender.send(EngineEvent::Connected {
+        connection_id,
+        sender: client_sender,
+    });
+    let _registration = EngineRegistration {
+        connection_id,
+        engine_sender: engine_sender.clone(),
+    };
 
-    // Send the inital package which includes the map
-    let full_save_state = shared_full_state.lock().unwrap().get(0).unwrap().clone();
-    ws_sender.send(Message::Text(full_save_state)).await?;
-    let mut num_full_state_sends: i8 = 5;
+    let mut client_snapshots = ClientSnapshots::new();
+    let mut protocol_offences = 0;
+    // Hold off on snapshots until the engine has told the client who they are
+    let mut welcomed = false;
 
-    let mut msg_fut = ws_receiver.next();
-    let mut tick_fut = interval.next();
     loop {
-        match select(msg_fut, tick_fut).await {
-            Either::Left((msg, tick_fut_continue)) => {
+        tokio::select! {
+            msg = ws_receiver.next() => {
                 match msg {
-                    Some(msg) => {
-                        let msg = msg?;
-                        if msg.is_text() || msg.is_binary() {
-                            if player_id.is_none() {
-                                let player_input = deserialize_player_input(msg.to_string());
-                                if let PlayerInput::CreatePlayer { id, name: _ } = player_input {
-                                    player_id = Some(id);
+                    Some(Err(error)) => {
+                        println!("Error receiving from {}: {}", peer, error);
+                        break;
+                    }
+                    Some(Ok(msg)) => {
+                        if msg.is_close() {
+                            break;
+                        } else if !msg.is_text() && !msg.is_binary() {
+                            continue;
+                        }
+                        let parsed = msg
+                            .to_text()
+                            .map_err(|e| ProtocolError::Malformed(e.to_string()))
+                            .and_then(deserialize_player_input);
+                        match parsed {
+                            Ok(PlayerInput::AckSnapshot { tick }) => {
+                                // Acknowledgements are for us, not the game engine
+                                client_snapshots.acknowledge(tick);
+                            }
+                            Ok(input) => {
+                                let _ = engine_sender.send(EngineEvent::Input {
+                                    connection_id,
+                                    input,
+                                });
+                            }
+                            Err(error) => {
+                                println!("Bad message from {}: {}", peer, error);
+                                protocol_offences += 1;
+                                let reply = ServerMessage::ProtocolError {
+                                    message: error.to_string(),
+                                };
+                                let encoded = match encode_server_message(&reply, wire_format) {
+                                    Ok(encoded) => encoded,
+                                    Err(error) => {
+                                        println!("Can't reply to {}: {}", peer, error);
+                                        break;
+                                    }
+                                };
+                                if ws_sender
+                                    .send(server_message(encoded, wire_format))
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                                if protocol_offences >= MAX_PROTOCOL_OFFENCES {
+                                    let close = CloseFrame {
+                                        code: CloseCode::Policy,
+                                        reason: "Too many malformed messages".into(),
+                                    };
+                                    let _ = ws_sender.send(Message::Close(Some(close))).await;
+                                    break;
                                 }
                             }
-                            // Push the message onto the player input queue
-                            shared_input_queue.lock().unwrap().push(msg.to_string());
-                        } else if msg.is_close() {
-                            break;
                         }
-                        tick_fut = tick_fut_continue; // Continue waiting for tick.
-                        msg_fut = ws_receiver.next(); // Receive next WebSocket message.
                     }
                     None => break, // WebSocket stream terminated.
+                }
+            }
+            Some(message) = client_receiver.recv() => {
+                // Messages the engine addressed to this client specifically
+                if let ServerMessage::Welcome { .. } = message {
+                    welcomed = true;
+                }
+                let encoded = match encode_server_message(&message, wire_format) {
+                    Ok(encoded) => encoded,
+                    Err(error) => {
+                        println!("Can't send a message to {}: {}", peer, error);
+                        break;
+                    }
                 };
+                if ws_sender
+                    .send(server_message(encoded, wire_format))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
             }
-            Either::Right((_, msg_fut_continue)) => {
-                let save_state;
-                if num_full_state_sends > 0 {
-                    // For the first few sends, send the full map to ensure they get the map
-                    save_state = shared_full_state.lock().unwrap().get(0).unwrap().clone();
-                    num_full_state_sends -= 1;
-                } else {
-                    save_state = shared_incr_state.lock().unwrap().get(0).unwrap().clone();
+            _ = interval.next() => {
+                if !welcomed {
+                    continue;
+                }
+                let published = snapshot_receiver.borrow().clone();
+                // The engine works out what we can see once it's dealt with our connection
+                let view = published
+                    .as_ref()
+                    .and_then(|published| published.clients.get(&connection_id));
+                if let (Some(published), Some(view)) = (&published, view) {
+                    match client_snapshots.package_next(published, view, wire_format) {
+                        Ok(Some(save_state)) => {
+                            if ws_sender
+                                .send(server_message(save_state, wire_format))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(error) => {
+                            println!("Can't send a snapshot to {}: {}", peer, error);
+                            break;
+                        }
+                    }
                 }
-                ws_sender.send(Message::Text(save_state)).await?;
-                msg_fut = msg_fut_continue; // Continue receiving the WebSocket message.
-                tick_fut = interval.next(); // Wait for next tick.
             }
         }
     }
 
     println!("Connection closed: {}", peer);
 
-    // Queue input to delete their entity if any
-    if let Some(player_id) = player_id {
-        let delete_input = serialize_player_input(PlayerInput::DeletePlayer { id: player_id });
-        shared_input_queue.lock().unwrap().push(delete_input);
-    }
-
     Ok(())
 }
 
+/// Tell the engine when we get ctrl-c, so it can save the world before exiting
+async fn forward_shutdown(engine_sender: UnboundedSender<EngineEvent>) {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        let _ = engine_sender.send(EngineEvent::Shutdown);
+    }
+}
+
 async fn run(
-    shared_full_state: AsyncStatePtr,
-    shared_incr_state: AsyncStatePtr,
-    shared_input_queue: AsyncStatePtr,
+    addr: SocketAddr,
+    send_interval: Duration,
+    snapshot_receiver: SnapshotReceiver,
+    engine_sender: UnboundedSender<EngineEvent>,
 ) {
-    let addr = "0.0.0.0:3012";
     let mut listener = TcpListener::bind(&addr).await.expect("Can't listen");
     println!("Listening on: {}", addr);
+    tokio::spawn(forward_shutdown(engine_sender.clone()));
 
     while let Ok((stream, _)) = listener.accept().await {
         let peer = stream
@@ -102,24 +405,130 @@ async fn run(
         tokio::spawn(handle_connection(
             peer,
             stream,
-            shared_full_state.clone(),
-            shared_incr_state.clone(),
-            shared_input_queue.clone(),
+            send_interval,
+            snapshot_receiver.clone(),
+            engine_sender.clone(),
         ));
     }
 }
 
 pub fn start_async_server(
-    shared_full_state: AsyncStatePtr,
-    shared_incr_state: AsyncStatePtr,
-    shared_input_queue: AsyncStatePtr,
+    addr: SocketAddr,
+    send_interval: Duration,
+    snapshot_receiver: SnapshotReceiver,
+    engine_sender: UnboundedSender<EngineEvent>,
 ) {
     thread::spawn(move || {
         let mut runtime = tokio::runtime::Runtime::new().unwrap();
-        runtime.block_on(run(
-            shared_full_state.clone(),
-            shared_incr_state.clone(),
-            shared_input_queue.clone(),
-        ));
+        runtime.block_on(run(addr, send_interval, snapshot_receiver, engine_sender));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ferris_chat::entities::spawn_crab;
+    use ferris_chat::prefabs::Prefabs;
+    use ferris_chat::saveload_system::{decode_server_message, load_game, serialize_map};
+    use ferris_chat::state::{initialize_ecs, State, WorldConfig};
+
+    fn new_world() -> State {
+        let mut gs = State::new();
+        initialize_ecs(&mut gs.ecs, &WorldConfig::default(), Prefabs::default());
+        gs
+    }
+
+    /// What the engine publishes after the first tick of a world with one player in it
+    fn published_world() -> PublishedState {
+        let mut gs = new_world();
+        spawn_crab(&mut gs.ecs, "player-0", "Ferris", false);
+        gs.tick();
+        let map = (*gs.ecs.fetch::<Map>()).clone();
+        PublishedState {
+            snapshot: Arc::new(gs.get_snapshot(gs.current_tick())),
+            map: Arc::new(map),
+            clients: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// A client allowed to see every entity in `published`
+    fn sees_everything(published: &PublishedState) -> ClientView {
+        ClientView {
+            interest: published.snapshot.entities.keys().cloned().collect(),
+            last_input_sequence: None,
+        }
+    }
+
+    /// The handshake a browser sends when the client connects
+    fn client_request() -> Request {
+        let protocols: Vec<&str> = WireFormat::PREFERRED
+            .iter()
+            .map(WireFormat::protocol_name)
+            .collect();
+        Request::builder()
+            .uri("ws://localhost:3012/")
+            .header("Sec-WebSocket-Protocol", protocols.join(", "))
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn full_snapshots_back_off_until_one_is_acknowledged() {
+        let mut published = published_world();
+        let view = sees_everything(&published);
+        let mut client = ClientSnapshots::new();
+        let send_next = |published: &mut PublishedState, client: &mut ClientSnapshots| {
+            // The world moves on a tick every send interval
+            let mut snapshot = (*published.snapshot).clone();
+            snapshot.tick += 1;
+            published.snapshot = Arc::new(snapshot);
+            client
+                .package_next(published, &view, WireFormat::Json)
+                .expect("Failed to encode save state")
+                .is_some()
+        };
+
+        let sent: Vec<u32> = (0..64)
+            .filter(|_| send_next(&mut published, &mut client))
+            .collect();
+        assert_eq!(sent, vec![0, 2, 5, 10, 19, 36]);
+
+        // Once the client catches up it gets a delta every interval
+        let last_sent_tick = client.unacked.back().unwrap().tick;
+        client.acknowledge(last_sent_tick);
+        assert!((0..10).all(|_| send_next(&mut published, &mut client)));
+    }
+
+    #[test]
+    fn clients_load_the_compressed_saves_they_ask_for() {
+        let format = negotiate_wire_format(&client_request()).expect("No format in common");
+        assert_eq!(format, WireFormat::CompressedMessagePack);
+
+        let published = published_world();
+        let encoded = ClientSnapshots::new()
+            .package_next(&published, &sees_everything(&published), format)
+            .expect("Failed to encode save state")
+            .expect("Nothing to send");
+        let frame = match server_message(encoded, format) {
+            Message::Binary(frame) => frame,
+            _ => panic!("Compressed saves should be sent as binary frames"),
+        };
+        let save_state = match decode_server_message(&frame, format) {
+            Ok(ServerMessage::SaveState(save_state)) => save_state,
+            Ok(_) => panic!("Decoded the wrong message"),
+            Err(error) => panic!("Failed to decode save state: {}", error),
+        };
+
+        let mut client = new_world();
+        assert_eq!(load_game(&mut client.ecs, save_state), Ok(true));
+        let tick = published.snapshot.tick;
+        assert_eq!(
+            client.get_snapshot(tick).entities,
+            published.snapshot.entities
+        );
+        assert_eq!(
+            serialize_map(&client.ecs.fetch::<Map>()),
+            serialize_map(&published.map)
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use crate::components::Location;
+use specs::prelude::*;
+use std::collections::HashMap;
+
+/// Width and height (in tiles) of each bucket in the SpatialIndex
+const CELL_SIZE: i32 = 8;
+
+/// Buckets every entity with a Location by area so we can find what's nearby without
+/// checking everything against everything. Rebuilt each tick once things have moved.
+#[derive(Default)]
+pub struct SpatialIndex {
+    cells: HashMap<(i32, i32), Vec<(Entity, Location)>>,
+}
+
+fn cell_for(x: i32, y: i32) -> (i32, i32) {
+    (x.div_euclid(CELL_SIZE), y.div_euclid(CELL_SIZE))
+}
+
+impl SpatialIndex {
+    pub fn clear(&mut self) {
+        // Keep the buckets around so we don't reallocate them every tick
+        for entities in self.cells.values_mut() {
+            entities.clear();
+        }
+    }
+
+    pub fn insert(&mut self, entity: Entity, location: &Location) {
+        self.cells
+            .entry(cell_for(location.x, location.y))
+            .or_default()
+            .push((entity, location.clone()));
+    }
+
+    /// Everything strictly closer than `radius` to `center`
+    pub fn query_radius(&self, center: &Location, radius: f64) -> Vec<Entity> {
+        let reach = radius.ceil() as i32;
+        let (min_cell_x, min_cell_y) = cell_for(center.x - reach, center.y - reach);
+        let (max_cell_x, max_cell_y) = cell_for(center.x + reach, center.y + reach);
+        let radius_squared = radius * radius;
+        let mut found = Vec::new();
+        for cell_x in min_cell_x..=max_cell_x {
+            for cell_y in min_cell_y..=max_cell_y {
+                if let Some(entities) = self.cells.get(&(cell_x, cell_y)) {
+                    found.extend(
+                        entities
+                            .iter()
+                            .filter(|(_, location)| {
+                                // Compare squared distances to save ourselves the sqrt
+                                let dx = (location.x - center.x) as f64;
+                                let dy = (location.y - center.y) as f64;
+                                dx * dx + dy * dy < radius_squared
+                            })
+                            .map(|(entity, _)| *entity),
+                    );
+                }
+            }
+        }
+        found
+    }
+}
+
+pub struct SpatialIndexSystem {}
+
+impl<'a> System<'a> for SpatialIndexSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Location>,
+        Write<'a, SpatialIndex>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, locations, mut spatial_index) = data;
+
+        spatial_index.clear();
+        for (entity, location) in (&entities, &locations).join() {
+            spatial_index.insert(entity, location);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::components::{Item, Location};
+use crate::map::{get_random_location_of_tile, Map};
+use crate::occupancy::Occupancy;
+use crate::prefabs::Prefabs;
+use oorandom::Rand32;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Random tiles we'll try for a new item before waiting for the next tick
+const MAX_SPAWN_ATTEMPTS: u32 = 10;
+
+/// How many of each item there should be on the island
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ItemSpawnConfig {
+    /// Number of each item prefab to keep around, by prefab name
+    pub targets: BTreeMap<String, u32>,
+    /// Ticks to wait after an item goes missing before replacing it
+    pub respawn_ticks: u32,
+}
+
+impl Default for ItemSpawnConfig {
+    fn default() -> ItemSpawnConfig {
+        let mut targets = BTreeMap::new();
+        for name in &["knife", "hat", "glasses"] {
+            targets.insert(String::from(*name), 1);
+        }
+        ItemSpawnConfig {
+            targets,
+            respawn_ticks: 100,
+        }
+    }
+}
+
+impl ItemSpawnConfig {
+    pub fn total(&self) -> u32 {
+        self.targets.values().sum()
+    }
+}
+
+/// Random spot for a new item that nothing is standing on, if we can find one
+pub fn item_spawn_location(
+    map: &Map,
+    occupancy: &Occupancy,
+    prefabs: &Prefabs,
+    rng: &mut Rand32,
+    name: &str,
+) -> Option<Location> {
+    let spawn_tile = prefabs.get(name).and_then(|prefab| prefab.spawn_tile);
+    for _ in 0..MAX_SPAWN_ATTEMPTS {
+        let location = get_random_location_of_tile(map, rng, spawn_tile)?;
+        if !occupancy.is_blocked(location.x, location.y) {
+            return Some(location);
+        }
+    }
+    None
+}
+
+/// Keeps track of how long until each missing item comes back
+pub struct ItemSpawner {
+    pub config: ItemSpawnConfig,
+    cooldowns: HashMap<String, u32>,
+}
+
+impl ItemSpawner {
+    pub fn new(config: &ItemSpawnConfig) -> ItemSpawner {
+        ItemSpawner {
+            config: config.clone(),
+            cooldowns: HashMap::new(),
+        }
+    }
+}
+
+/// Tops the island back up to the configured number of each item. Items being carried
+/// count too, otherwise crabs could hoard as many as they liked.
+pub struct ItemSpawnerSystem {}
+
+impl<'a> System<'a> for ItemSpawnerSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Map>,
+        ReadExpect<'a, Prefabs>,
+        Read<'a, Occupancy>,
+        WriteExpect<'a, Rand32>,
+        WriteExpect<'a, ItemSpawner>,
+        ReadStorage<'a, Item>,
+        Read<'a, LazyUpdate>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, map, prefabs, occupancy, mut rng, mut spawner, items, lazy) = data;
+
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for item in items.join() {
+            *counts.entry(&item.kind).or_default() += 1;
+        }
+
+        // Targets are sorted by name, so we always go through them in the same order and
+        // the rng gives the same results
+        let ItemSpawner { config, cooldowns } = &mut *spawner;
+        for (name, target) in config.targets.iter() {
+            let cooldown = cooldowns
+                .entry(name.clone())
+                .or_insert(config.respawn_ticks);
+            if counts.get(name.as_str()).cloned().unwrap_or(0) >= *target {
+                // Nothing missing, so start counting down again once something is
+                *cooldown = config.respawn_ticks;
+                continue;
+            }
+            if *cooldown > 0 {
+                *cooldown -= 1;
+                continue;
+            }
+            if let Some(location) = item_spawn_location(&map, &occupancy, &prefabs, &mut rng, name)
+            {
+                prefabs
+                    .build(name, lazy.create_entity(&entities))
+                    .with(location)
+                    .build();
+                *cooldown = config.respawn_ticks;
+            }
+        }
+    }
+}
@@ -2,18 +2,25 @@ extern crate serde;
 
 use censor::*;
 use oorandom::Rand32;
+use serde::{Deserialize, Serialize};
 use specs::prelude::*;
 use specs::saveload::{SimpleMarker, SimpleMarkerAllocator};
 
-use crate::animation::{AnimationSystem, DisappearingSystem};
-use crate::carry::{CarrySystem, PickUpSystem};
+use crate::carry::{
+    drop_item, equipped_in, find_room, inventory_count, pick_up_item, stow_item, Stow,
+    INVENTORY_SIZE,
+};
 use crate::components::*;
-use crate::crab_ai::CrabAISystem;
 use crate::entities::*;
-use crate::map::{valid_walking_location, Map};
-use crate::movement::MovementSystem;
-use crate::saveload_system::{serialize_ecs, serialize_map, PlayerInput};
-use crate::weapons::StabSystem;
+use crate::map::{euclidean_distance, valid_walking_location, Map, PICKUP_DISTANCE};
+use crate::prefabs::{spawn_prefab, Prefabs};
+use crate::saveload_system::{
+    deserialize_ecs, register_components, serialize_ecs, serialize_map, snapshot_ecs,
+    AppliedSnapshot, PlayerInput, WorldSnapshot, COMPONENT_REGISTRY_VERSION,
+};
+use crate::scheduler::{build_dispatcher, Time};
+use crate::spatial::SpatialIndex;
+use crate::spawner::{ItemSpawnConfig, ItemSpawner};
 
 pub fn handle_input(ecs: &mut World, input: &str, player_id: &String) {
     let maybe_entity;
@@ -39,7 +46,7 @@ pub fn handle_input(ecs: &mut World, input: &str, player_id: &String) {
 
     match input {
         "p" => {
-            create_poop(ecs, Location { x: new_x, y: new_y });
+            spawn_prefab(ecs, "poop", Location { x: new_x, y: new_y });
             return;
         }
         _ => return,
@@ -57,8 +64,15 @@ pub fn handle_click(ecs: &mut World, x: i32, y: i32, player_id: &String) {
     }
 
     let for_entity = maybe_entity.unwrap();
+    if ecs.read_storage::<Dead>().get(for_entity).is_some() {
+        return; // Can't walk anywhere until we respawn
+    }
     let map = ecs.fetch::<Map>();
-    let desired_location = WantsToMoveTo { x, y, speed: 2 };
+    let desired_location = WantsToMoveTo {
+        x,
+        y,
+        speed: PLAYER_WALK_SPEED,
+    };
     if valid_walking_location(&map, &desired_location) {
         let mut move_tos = ecs.write_storage::<WantsToMoveTo>();
         move_tos
@@ -67,12 +81,73 @@ pub fn handle_click(ecs: &mut World, x: i32, y: i32, player_id: &String) {
     }
 }
 
+/// Movement points a player crab gets each tick (see map::movement_cost)
+pub const PLAYER_WALK_SPEED: i16 = 2;
+
 /// Censor any profanity considering we're about to render the input
 pub fn censor_chat_input(chat_input: &str) -> String {
     let censor = Censor::Standard + "cunk";
     censor.censor(chat_input)
 }
 
+/// Longest name a crab can have, in characters
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Restrict names to characters we can render and a length that fits above a crab.
+/// Returns None if nothing usable is left.
+pub fn sanitize_player_name(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || " -_'.".contains(*c))
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some(censor_chat_input(name))
+}
+
+/// Rename the player's crab and let everyone around them know
+pub fn handle_change_name(ecs: &mut World, name: &str, player_id: &String) {
+    let maybe_entity;
+    {
+        maybe_entity = get_player_with_id(ecs, player_id);
+        if maybe_entity.is_none() {
+            println!("Entity ID {} doesn't exist!", &player_id);
+            return;
+        }
+    }
+
+    let new_name = match sanitize_player_name(name) {
+        Some(new_name) => new_name,
+        None => {
+            println!("Rejected name change for {}", &player_id);
+            return;
+        }
+    };
+
+    let for_entity = maybe_entity.unwrap();
+    let old_name;
+    {
+        let mut text_renderables = ecs.write_storage::<TextRenderable>();
+        let text_renderable = text_renderables
+            .get_mut(for_entity)
+            .expect("Cannot find name for player");
+        if text_renderable.text == new_name {
+            return;
+        }
+        old_name = std::mem::replace(&mut text_renderable.text, new_name.clone());
+    }
+    let announcement = if old_name.is_empty() {
+        format!("Call me {}!", new_name)
+    } else {
+        format!("{} is now {}!", old_name, new_name)
+    };
+    create_chat_bubble(ecs, announcement, for_entity);
+}
+
 /// Censor any profanity considering we're about to render the input
 pub fn handle_chat_input(mut ecs: &mut World, chat_input: &str, player_id: &String) {
     let maybe_entity;
@@ -88,29 +163,333 @@ pub fn handle_chat_input(mut ecs: &mut World, chat_input: &str, player_id: &Stri
     create_chat_bubble(&mut ecs, censor_chat_input(&chat_input), for_entity);
 }
 
+/// The living crab belonging to `player_id` and where it is, if it can act right now
+fn living_player(ecs: &World, player_id: &String) -> Option<(Entity, Location)> {
+    let entity = match get_player_with_id(ecs, player_id) {
+        Some(entity) => entity,
+        None => {
+            println!("Entity ID {} doesn't exist!", &player_id);
+            return None;
+        }
+    };
+    if ecs.read_storage::<Dead>().get(entity).is_some() {
+        return None; // Dead crabs can't do much of anything
+    }
+    let location = ecs
+        .read_storage::<Location>()
+        .get(entity)
+        .expect("Cannot find location for player")
+        .clone();
+    Some((entity, location))
+}
+
+/// Everything `player_entity` is carrying, equipped or not
+fn carried_items(ecs: &World, player_entity: Entity) -> Vec<Entity> {
+    let entities = ecs.entities();
+    let carried_bys = ecs.read_storage::<CarriedBy>();
+    let items = ecs.read_storage::<Item>();
+    (&entities, &carried_bys, &items)
+        .join()
+        .filter(|(_, carried_by, _)| carried_by.owner == player_entity)
+        .map(|(item_entity, _, _)| item_entity)
+        .collect()
+}
+
+/// Pick up the closest item in reach that the player has room for, whether or not
+/// auto-pickup is on. Items that were just dropped can be grabbed straight away when
+/// asked for explicitly.
+pub fn handle_pick_up(ecs: &mut World, player_id: &String) {
+    let (player_entity, player_location) = match living_player(ecs, player_id) {
+        Some(player) => player,
+        None => return,
+    };
+
+    let nearby = ecs
+        .fetch::<SpatialIndex>()
+        .query_radius(&player_location, PICKUP_DISTANCE);
+    let entities = ecs.entities();
+    let locations = ecs.read_storage::<Location>();
+    let items = ecs.read_storage::<Item>();
+    let mut pickups = ecs.write_storage::<WantsToBePickedUp>();
+    let mut carried_bys = ecs.write_storage::<CarriedBy>();
+    let mut renders = ecs.write_storage::<Renderable>();
+    let mut equippeds = ecs.write_storage::<Equipped>();
+
+    let closest_item = nearby
+        .into_iter()
+        .filter_map(|item_entity| {
+            let location = locations.get(item_entity)?;
+            let item = items.get(item_entity)?;
+            pickups.get(item_entity)?;
+            let stow = find_room(
+                player_entity,
+                item,
+                &entities,
+                &carried_bys,
+                &items,
+                &equippeds,
+            )?;
+            Some((
+                euclidean_distance(&player_location, location),
+                item_entity,
+                stow,
+            ))
+        })
+        .min_by(|a, b| {
+            a.0.partial_cmp(&b.0)
+                .expect("Distance is not a number")
+                .then_with(|| a.1.id().cmp(&b.1.id()))
+        });
+
+    if let Some((_, item_entity, stow)) = closest_item {
+        pick_up_item(
+            item_entity,
+            player_entity,
+            stow,
+            &mut carried_bys,
+            &mut pickups,
+            &mut renders,
+            &mut equippeds,
+        );
+    }
+}
+
+/// Put down everything the player is carrying where they're standing
+pub fn handle_drop(ecs: &mut World, player_id: &String) {
+    let (player_entity, _) = match living_player(ecs, player_id) {
+        Some(player) => player,
+        None => return,
+    };
+
+    let dropped = carried_items(ecs, player_entity);
+    let items = ecs.read_storage::<Item>();
+    let mut carried_bys = ecs.write_storage::<CarriedBy>();
+    let mut pickups = ecs.write_storage::<WantsToBePickedUp>();
+    let mut renders = ecs.write_storage::<Renderable>();
+    let mut equippeds = ecs.write_storage::<Equipped>();
+    for item_entity in dropped {
+        let item = items.get(item_entity).expect("Cannot find item");
+        drop_item(
+            item_entity,
+            item,
+            &mut carried_bys,
+            &mut pickups,
+            &mut renders,
+            &mut equippeds,
+        );
+    }
+}
+
+/// Hand everything the player is carrying to the closest living crab in reach. Anything
+/// the other crab doesn't have room for stays with the player.
+pub fn handle_give(ecs: &mut World, player_id: &String) {
+    let (player_entity, player_location) = match living_player(ecs, player_id) {
+        Some(player) => player,
+        None => return,
+    };
+
+    let closest_crab;
+    {
+        let spatial_index = ecs.fetch::<SpatialIndex>();
+        let locations = ecs.read_storage::<Location>();
+        let player_infos = ecs.read_storage::<PlayerInfo>();
+        let deads = ecs.read_storage::<Dead>();
+        closest_crab = spatial_index
+            .query_radius(&player_location, PICKUP_DISTANCE)
+            .into_iter()
+            .filter(|entity| {
+                *entity != player_entity
+                    && player_infos.get(*entity).is_some()
+                    && deads.get(*entity).is_none()
+            })
+            .filter_map(|entity| {
+                let location = locations.get(entity)?;
+                Some((euclidean_distance(&player_location, location), entity))
+            })
+            .min_by(|a, b| {
+                a.0.partial_cmp(&b.0)
+                    .expect("Distance is not a number")
+                    .then_with(|| a.1.id().cmp(&b.1.id()))
+            })
+            .map(|(_, entity)| entity);
+    }
+
+    let receiver = match closest_crab {
+        Some(receiver) => receiver,
+        None => return, // Nobody to give anything to
+    };
+    let given = carried_items(ecs, player_entity);
+    let entities = ecs.entities();
+    let items = ecs.read_storage::<Item>();
+    let mut carried_bys = ecs.write_storage::<CarriedBy>();
+    let mut renders = ecs.write_storage::<Renderable>();
+    let mut equippeds = ecs.write_storage::<Equipped>();
+    for item_entity in given {
+        let item = items.get(item_entity).expect("Cannot find item");
+        if let Some(stow) = find_room(receiver, item, &entities, &carried_bys, &items, &equippeds) {
+            stow_item(
+                item_entity,
+                receiver,
+                stow,
+                &mut carried_bys,
+                &mut renders,
+                &mut equippeds,
+            );
+        }
+    }
+}
+
+/// Equip the next item in the player's inventory that goes in `slot`, putting whatever
+/// was there before away. Doing it again cycles through everything that fits.
+pub fn handle_swap(ecs: &mut World, slot: EquipmentSlot, player_id: &String) {
+    let (player_entity, _) = match living_player(ecs, player_id) {
+        Some(player) => player,
+        None => return,
+    };
+
+    let entities = ecs.entities();
+    let items = ecs.read_storage::<Item>();
+    let mut carried_bys = ecs.write_storage::<CarriedBy>();
+    let mut renders = ecs.write_storage::<Renderable>();
+    let mut equippeds = ecs.write_storage::<Equipped>();
+
+    let current = equipped_in(player_entity, slot, &entities, &carried_bys, &equippeds);
+    let mut candidates: Vec<Entity> = (&entities, &carried_bys, &items, !&equippeds)
+        .join()
+        .filter(|(_, carried_by, item, _)| carried_by.owner == player_entity && item.slot == slot)
+        .map(|(item_entity, _, _, _)| item_entity)
+        .collect();
+    candidates.sort_by_key(|item_entity| item_entity.id());
+    let after_current = current.map_or(0, |current| current.id() + 1);
+    let next = match candidates
+        .iter()
+        .find(|item_entity| item_entity.id() >= after_current)
+        .or_else(|| candidates.first())
+    {
+        Some(next) => *next,
+        None => return, // Nothing to swap to
+    };
+
+    if let Some(current) = current {
+        stow_item(
+            current,
+            player_entity,
+            Stow::Inventory,
+            &mut carried_bys,
+            &mut renders,
+            &mut equippeds,
+        );
+    }
+    stow_item(
+        next,
+        player_entity,
+        Stow::Equip(slot),
+        &mut carried_bys,
+        &mut renders,
+        &mut equippeds,
+    );
+}
+
+/// Take off whatever's in `slot` and put it in the player's inventory, or on the ground
+/// if the inventory is full
+pub fn handle_unequip(ecs: &mut World, slot: EquipmentSlot, player_id: &String) {
+    let (player_entity, _) = match living_player(ecs, player_id) {
+        Some(player) => player,
+        None => return,
+    };
+
+    let entities = ecs.entities();
+    let items = ecs.read_storage::<Item>();
+    let mut carried_bys = ecs.write_storage::<CarriedBy>();
+    let mut pickups = ecs.write_storage::<WantsToBePickedUp>();
+    let mut renders = ecs.write_storage::<Renderable>();
+    let mut equippeds = ecs.write_storage::<Equipped>();
+
+    let item_entity = match equipped_in(player_entity, slot, &entities, &carried_bys, &equippeds) {
+        Some(item_entity) => item_entity,
+        None => return,
+    };
+    if inventory_count(player_entity, &carried_bys, &items, &equippeds) < INVENTORY_SIZE {
+        stow_item(
+            item_entity,
+            player_entity,
+            Stow::Inventory,
+            &mut carried_bys,
+            &mut renders,
+            &mut equippeds,
+        );
+    } else {
+        let item = items.get(item_entity).expect("Cannot find item");
+        drop_item(
+            item_entity,
+            item,
+            &mut carried_bys,
+            &mut pickups,
+            &mut renders,
+            &mut equippeds,
+        );
+    }
+}
+
+/// Turn picking up items just by walking near them on or off
+pub fn handle_set_auto_pickup(ecs: &mut World, enabled: bool, player_id: &String) {
+    let for_entity = match get_player_with_id(ecs, player_id) {
+        Some(entity) => entity,
+        None => {
+            println!("Entity ID {} doesn't exist!", &player_id);
+            return;
+        }
+    };
+
+    let mut auto_pickups = ecs.write_storage::<AutoPickup>();
+    if enabled {
+        auto_pickups
+            .insert(for_entity, AutoPickup {})
+            .expect("Unable to insert AutoPickup");
+    } else {
+        auto_pickups.remove(for_entity);
+    }
+}
+
+/// Whether the player's crab picks things up just by walking near them
+pub fn auto_pickup_enabled(ecs: &World, player_id: &String) -> bool {
+    match get_player_with_id(ecs, player_id) {
+        Some(entity) => ecs.read_storage::<AutoPickup>().get(entity).is_some(),
+        None => false,
+    }
+}
+
 pub struct State {
     pub ecs: World,
+    dispatcher: Dispatcher<'static, 'static>,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::new()
+    }
 }
 
 impl State {
+    pub fn new() -> State {
+        let mut ecs = World::new();
+        let mut dispatcher = build_dispatcher();
+        // Register everything our systems need, including their event readers
+        dispatcher.setup(&mut ecs);
+        State { ecs, dispatcher }
+    }
+
     fn run_systems(&mut self) {
-        let mut movement_system = MovementSystem {};
-        movement_system.run_now(&self.ecs);
-        let mut disappearing_system = DisappearingSystem {};
-        disappearing_system.run_now(&self.ecs);
-        let mut pick_up_system = PickUpSystem {}; // PickUp before Carry so we can update location
-        pick_up_system.run_now(&self.ecs);
-        let mut carry_system = CarrySystem {};
-        carry_system.run_now(&self.ecs);
-        let mut crab_ai_system = CrabAISystem {};
-        crab_ai_system.run_now(&self.ecs);
-        StabSystem::run_now_manually(&mut self.ecs);
-        let mut animation_system = AnimationSystem {};
-        animation_system.run_now(&self.ecs);
+        self.dispatcher.dispatch(&self.ecs);
 
         self.ecs.maintain();
     }
 
+    /// Number of ticks simulated so far
+    pub fn current_tick(&self) -> u64 {
+        self.ecs.fetch::<Time>().tick
+    }
+
     pub fn get_serialized_map(&self) -> String {
         serialize_map(&self.ecs.fetch::<Map>())
     }
@@ -119,15 +498,40 @@ impl State {
         serialize_ecs(&mut self.ecs)
     }
 
-    pub fn handle_player_input(&mut self, player_input: PlayerInput) {
+    pub fn get_save_metadata(&self) -> SaveMetadata {
+        SaveMetadata {
+            format_version: SAVE_FORMAT_VERSION,
+            component_registry_version: COMPONENT_REGISTRY_VERSION,
+            tick: self.current_tick(),
+            rng_state: self.ecs.fetch::<Rand32>().state(),
+        }
+    }
+
+    pub fn get_snapshot(&self, tick: u64) -> WorldSnapshot {
+        snapshot_ecs(&self.ecs, tick)
+    }
+
+    pub fn handle_player_input(&mut self, id: &String, player_input: PlayerInput) {
         match player_input {
-            PlayerInput::CreatePlayer { id, name } => {
-                spawn_crab(&mut self.ecs, &id, &censor_chat_input(&name), false)
+            PlayerInput::CreatePlayer { name } => spawn_crab(
+                &mut self.ecs,
+                id,
+                &sanitize_player_name(&name).unwrap_or_default(),
+                false,
+            ),
+            PlayerInput::ChangeName { name } => handle_change_name(&mut self.ecs, &name, id),
+            PlayerInput::DeletePlayer => delete_player_with_id(&mut self.ecs, id),
+            PlayerInput::SpecialInput { input } => handle_input(&mut self.ecs, &input, id),
+            PlayerInput::Click { x, y, .. } => handle_click(&mut self.ecs, x, y, id),
+            PlayerInput::Chat { message } => handle_chat_input(&mut self.ecs, &message, id),
+            PlayerInput::PickUp => handle_pick_up(&mut self.ecs, id),
+            PlayerInput::Drop => handle_drop(&mut self.ecs, id),
+            PlayerInput::Give => handle_give(&mut self.ecs, id),
+            PlayerInput::Swap { slot } => handle_swap(&mut self.ecs, slot, id),
+            PlayerInput::Unequip { slot } => handle_unequip(&mut self.ecs, slot, id),
+            PlayerInput::SetAutoPickup { enabled } => {
+                handle_set_auto_pickup(&mut self.ecs, enabled, id)
             }
-            PlayerInput::DeletePlayer { id } => delete_player_with_id(&mut self.ecs, &id),
-            PlayerInput::SpecialInput { id, input } => handle_input(&mut self.ecs, &input, &id),
-            PlayerInput::Click { id, x, y } => handle_click(&mut self.ecs, x, y, &id),
-            PlayerInput::Chat { id, message } => handle_chat_input(&mut self.ecs, &message, &id),
             _ => {}
         }
     }
@@ -135,43 +539,269 @@ impl State {
     pub fn tick(&mut self) {
         // Run all our ECS systems
         self.run_systems();
+        self.ecs.write_resource::<Time>().tick += 1;
+    }
+}
+
+/// Everything needed to generate a new world
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldConfig {
+    pub width: i32,
+    pub height: i32,
+    pub seed: u64,
+    /// Number of computer controlled crabs wandering the island
+    pub ai_crabs: u32,
+    pub trees: u32,
+    /// How many of each item to keep around the island
+    pub items: ItemSpawnConfig,
+}
+
+impl Default for WorldConfig {
+    fn default() -> WorldConfig {
+        WorldConfig {
+            width: 100,
+            height: 100,
+            seed: 1,
+            ai_crabs: 2,
+            trees: 20,
+            items: ItemSpawnConfig::default(),
+        }
     }
 }
 
-pub fn initialize_ecs(mut ecs: &mut World, width: i32, height: i32, seed: u64) {
-    ecs.register::<FPSTracker>();
-    ecs.register::<Location>();
-    ecs.register::<PlayerInfo>();
-    ecs.register::<Renderable>();
-    ecs.register::<TextRenderable>();
-    ecs.register::<ChatRenderable>();
-    ecs.register::<GraphicRenderable>();
-    ecs.register::<GraphicAnimatable>();
-    ecs.register::<WantsToMoveTo>();
-    ecs.register::<Disappearing>();
-    ecs.register::<CarriedBy>();
-    ecs.register::<CrabAI>();
-    ecs.register::<WantsToBePickedUp>();
-    ecs.register::<WantsToStab>();
+/// Names for our AI crabs. If we need more crabs than names, they get numbered.
+const AI_CRAB_NAMES: [&str; 2] = ["Chris", "Tammy"];
+
+/// Register components and insert the resources every world needs, however it's built
+fn insert_resources(ecs: &mut World, config: &WorldConfig, prefabs: Prefabs) {
+    register_components(ecs);
 
     // Serialization helpers
     ecs.register::<SimpleMarker<EntityMarker>>();
     ecs.insert(SimpleMarkerAllocator::<EntityMarker>::new());
+    ecs.insert(AppliedSnapshot::default());
+    ecs.insert(Time::default());
+    ecs.insert(prefabs);
+    ecs.insert(ItemSpawner::new(&config.items));
+}
+
+pub fn initialize_ecs(ecs: &mut World, config: &WorldConfig, prefabs: Prefabs) {
+    insert_resources(ecs, config, prefabs);
 
     // Psuedo random number generator we'll use
-    let mut rng = Rand32::new(seed);
+    let mut rng = Rand32::new(config.seed);
 
     // Map contains the map state
-    let map = Map::new(&mut rng, width, height);
+    let map = Map::new(&mut rng, config.width, config.height);
 
     // Create some initial entities to our map
-    fill_map(&mut ecs, &map, &mut rng);
+    fill_map(ecs, &map, &mut rng, config.trees, &config.items);
 
     // Insert resources into ECS
     ecs.insert(map);
     ecs.insert(rng);
 
     // Create our crabs
-    spawn_crab(&mut ecs, "Chris", "Chris", true);
-    spawn_crab(&mut ecs, "Tammy", "Tammy", true);
+    for index in 0..config.ai_crabs as usize {
+        let base_name = AI_CRAB_NAMES[index % AI_CRAB_NAMES.len()];
+        let name = match index / AI_CRAB_NAMES.len() {
+            0 => String::from(base_name),
+            round => format!("{} {}", base_name, round + 1),
+        };
+        spawn_crab(ecs, &name, &name, true);
+    }
+}
+
+/// Version of the layout of the save files. Components are saved by position rather than
+/// by name, so saves also record the COMPONENT_REGISTRY_VERSION they were written with.
+pub const SAVE_FORMAT_VERSION: u32 = 1;
+
+/// Everything saved alongside the map and ECS so a restored world carries on where it
+/// left off
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SaveMetadata {
+    /// SAVE_FORMAT_VERSION of whatever wrote the save. Saves from before it was recorded
+    /// count as version 0.
+    #[serde(default)]
+    pub format_version: u32,
+    /// COMPONENT_REGISTRY_VERSION of whatever wrote the save
+    #[serde(default)]
+    pub component_registry_version: u32,
+    pub tick: u64,
+    pub rng_state: (u64, u64),
+}
+
+/// Build the world back up from a save instead of generating a new one. The map and item
+/// targets come from the save and config respectively, so the map size and seed in
+/// `config` are ignored.
+pub fn restore_ecs(
+    ecs: &mut World,
+    config: &WorldConfig,
+    prefabs: Prefabs,
+    map: Map,
+    serialized_ecs: &str,
+    metadata: &SaveMetadata,
+) -> serde_json::Result<()> {
+    insert_resources(ecs, config, prefabs);
+    ecs.insert(map);
+    ecs.insert(Rand32::from_state(metadata.rng_state));
+    ecs.write_resource::<Time>().tick = metadata.tick;
+
+    deserialize_ecs(ecs, serialized_ecs)?;
+    remove_transient_entities(ecs);
+    ecs.maintain();
+    Ok(())
+}
+
+/// Get rid of anything that only made sense while the server that saved the world was
+/// running: crabs belonging to players who were connected, their chat bubbles and any
+/// FPS trackers. Whatever those players were carrying is left on the ground.
+fn remove_transient_entities(ecs: &mut World) {
+    let players: Vec<Entity>;
+    {
+        let entities = ecs.entities();
+        let player_infos = ecs.read_storage::<PlayerInfo>();
+        let crab_ais = ecs.read_storage::<CrabAI>();
+        players = (&entities, &player_infos, !&crab_ais)
+            .join()
+            .map(|(entity, _, _)| entity)
+            .collect();
+    }
+    for player in players.iter() {
+        let location = ecs.read_storage::<Location>().get(*player).cloned();
+        for item_entity in carried_items(ecs, *player) {
+            if let Some(location) = location.clone() {
+                ecs.write_storage::<Location>()
+                    .insert(item_entity, location)
+                    .expect("Failed to drop item where the player was");
+            }
+            let item = ecs
+                .read_storage::<Item>()
+                .get(item_entity)
+                .expect("Cannot find item")
+                .clone();
+            drop_item(
+                item_entity,
+                &item,
+                &mut ecs.write_storage::<CarriedBy>(),
+                &mut ecs.write_storage::<WantsToBePickedUp>(),
+                &mut ecs.write_storage::<Renderable>(),
+                &mut ecs.write_storage::<Equipped>(),
+            );
+        }
+        delete_player(&ecs.entities(), &ecs.read_storage::<CarriedBy>(), *player);
+    }
+
+    let entities = ecs.entities();
+    let fps_trackers = ecs.read_storage::<FPSTracker>();
+    let chat_renderables = ecs.read_storage::<ChatRenderable>();
+    for (entity, _) in (&entities, &fps_trackers).join() {
+        entities
+            .delete(entity)
+            .expect("Failed to delete FPSTracker");
+    }
+    for (entity, _) in (&entities, &chat_renderables).join() {
+        entities
+            .delete(entity)
+            .expect("Failed to delete chat bubble");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::saveload_system::{
+        deserialize_player_input, serialize_player_input, ProtocolError, MAX_PLAYER_INPUT_BYTES,
+    };
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    const PLAYER_ID: &str = "player-0";
+
+    fn new_world() -> State {
+        let mut gs = State::new();
+        initialize_ecs(&mut gs.ecs, &WorldConfig::default(), Prefabs::default());
+        spawn_crab(&mut gs.ecs, PLAYER_ID, "Ferris", false);
+        gs
+    }
+
+    /// Parse a message the way the server does, and pass it on to the engine if it's valid
+    fn receive(gs: &mut State, message: &str) {
+        if let Ok(input) = deserialize_player_input(message) {
+            gs.handle_player_input(&PLAYER_ID.to_string(), input);
+        }
+        gs.tick();
+    }
+
+    fn slot() -> impl Strategy<Value = EquipmentSlot> {
+        prop_oneof![
+            Just(EquipmentSlot::Head),
+            Just(EquipmentSlot::Face),
+            Just(EquipmentSlot::Hand),
+        ]
+    }
+
+    /// Any input a client could send, with anything at all in its fields
+    fn player_input() -> impl Strategy<Value = PlayerInput> {
+        prop_oneof![
+            any::<String>().prop_map(|name| PlayerInput::CreatePlayer { name }),
+            Just(PlayerInput::DeletePlayer),
+            any::<String>().prop_map(|name| PlayerInput::ChangeName { name }),
+            any::<String>().prop_map(|input| PlayerInput::SpecialInput { input }),
+            "[pqeg123!@#]".prop_map(|input| PlayerInput::SpecialInput { input }),
+            (any::<i32>(), any::<i32>(), any::<u32>())
+                .prop_map(|(x, y, sequence)| PlayerInput::Click { x, y, sequence }),
+            // Somewhere on the map too, so the crab actually goes places
+            (-1..101, -1..101).prop_map(|(x, y)| PlayerInput::Click { x, y, sequence: 0 }),
+            any::<String>().prop_map(|message| PlayerInput::Chat { message }),
+            any::<u64>().prop_map(|tick| PlayerInput::AckSnapshot { tick }),
+            any::<String>().prop_map(|token| PlayerInput::Resume { token }),
+            Just(PlayerInput::PickUp),
+            Just(PlayerInput::Drop),
+            Just(PlayerInput::Give),
+            slot().prop_map(|slot| PlayerInput::Swap { slot }),
+            slot().prop_map(|slot| PlayerInput::Unequip { slot }),
+            any::<bool>().prop_map(|enabled| PlayerInput::SetAutoPickup { enabled }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn parsing_any_string_never_panics(message in any::<String>()) {
+            let _ = deserialize_player_input(&message);
+        }
+
+        #[test]
+        fn oversized_messages_are_rejected(message in ".{1025,4096}") {
+            let too_large = ProtocolError::TooLarge {
+                size: message.len(),
+                max: MAX_PLAYER_INPUT_BYTES,
+            };
+            prop_assert_eq!(deserialize_player_input(&message).err(), Some(too_large));
+        }
+    }
+
+    proptest! {
+        // Every case builds a whole island, so don't build too many
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn engine_survives_json_shaped_garbage(
+            messages in vec(r#"\{"[A-Za-z]{0,16}":(\{.{0,40}\}|".{0,40}"|-?[0-9]{1,20}|null)\}"#, 1..10)
+        ) {
+            let mut gs = new_world();
+            for message in messages {
+                receive(&mut gs, &message);
+            }
+        }
+
+        #[test]
+        fn engine_survives_any_inputs(inputs in vec(player_input(), 1..30)) {
+            let mut gs = new_world();
+            for input in inputs {
+                receive(&mut gs, &serialize_player_input(input));
+            }
+        }
+    }
 }
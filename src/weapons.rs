@@ -1,46 +1,124 @@
-use crate::components::{CarriedBy, Location, PlayerInfo, WantsToStab};
-use crate::entities::{create_blood_splatter, delete_player};
-use crate::map::within_pickup_distance;
+use crate::carry::drop_item;
+use crate::components::{
+    CarriedBy, Dead, Equipped, Health, Item, Location, PlayerInfo, Renderable, WantsToBePickedUp,
+    WantsToMoveTo, WantsToStab,
+};
+use crate::events::{GameEvent, GameEvents};
+use crate::map::PICKUP_DISTANCE;
+use crate::spatial::SpatialIndex;
 use specs::prelude::*;
 
+/// How long a crab stays dead before respawning
+pub const RESPAWN_TICKS: u32 = 50;
+
 pub struct StabSystem {}
 
-/// StabSystem isn't a standard system because it needs to create entities
-/// and I didn't find any better way to do this
-impl StabSystem {
-    pub fn run_now_manually(mut ecs: &mut World) {
-        // Build list of stabbings for later so we don't modify holding immutable reference
-        let mut stabbings = Vec::new();
+impl<'a> System<'a> for StabSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, SpatialIndex>,
+        ReadStorage<'a, PlayerInfo>,
+        ReadStorage<'a, Location>,
+        ReadStorage<'a, Item>,
+        WriteStorage<'a, CarriedBy>,
+        WriteStorage<'a, WantsToStab>,
+        WriteStorage<'a, WantsToBePickedUp>,
+        WriteStorage<'a, Renderable>,
+        WriteStorage<'a, WantsToMoveTo>,
+        WriteStorage<'a, Health>,
+        WriteStorage<'a, Dead>,
+        WriteStorage<'a, Equipped>,
+        Write<'a, GameEvents>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            spatial_index,
+            player_infos,
+            locations,
+            items,
+            mut carried_bys,
+            mut stabbies,
+            mut pickups,
+            mut renders,
+            mut move_tos,
+            mut healths,
+            mut deads,
+            mut equippeds,
+            mut events,
+        ) = data;
+
+        // Build list of kills for later so we don't modify holding immutable reference
+        let mut kills: Vec<(Entity, Entity, Location)> = Vec::new();
+
+        // A WantsToStab can only hurt if it's being held, not stowed in an inventory
+        for (item_location, carried_by, _, stabby) in
+            (&locations, &carried_bys, &equippeds, &mut stabbies).join()
         {
-            let entities = ecs.entities();
-            let player_infos = ecs.read_storage::<PlayerInfo>();
-            let locations = ecs.read_storage::<Location>();
-            let carried_bys = ecs.read_storage::<CarriedBy>();
-            let stabbies = ecs.read_storage::<WantsToStab>();
-
-            // A WantsToStab can only kill if it has a CarriedBy
-            for (item_location, carried_by, _) in (&locations, &carried_bys, &stabbies).join() {
-                // WantsToStab can only kill PlayerInfos (and can't be the carrier)
-                for (player_entity, player_location, _) in
-                    (&entities, &locations, &player_infos).join()
-                {
-                    if player_entity != carried_by.owner {
-                        if within_pickup_distance(item_location, player_location) {
-                            stabbings.push((player_entity, player_location.clone()));
-                        }
-                    }
+            if stabby.ticks_until_ready > 0 {
+                stabby.ticks_until_ready -= 1;
+                continue;
+            }
+            // WantsToStab can only hurt living PlayerInfos (and can't be the carrier)
+            let mut victims: Vec<Entity> = spatial_index
+                .query_radius(item_location, PICKUP_DISTANCE)
+                .into_iter()
+                .filter(|entity| {
+                    *entity != carried_by.owner
+                        && player_infos.get(*entity).is_some()
+                        && deads.get(*entity).is_none()
+                })
+                .collect();
+            // Always hurt in the same order so the simulation is reproducible
+            victims.sort_by_key(|entity| entity.id());
+            for victim in victims {
+                let health = match healths.get_mut(victim) {
+                    Some(health) if health.current > 0 => health,
+                    _ => continue,
+                };
+                health.current -= stabby.damage;
+                stabby.ticks_until_ready = stabby.cooldown_ticks;
+                if health.current <= 0 {
+                    let location = locations.get(victim).expect("Victim has no location");
+                    kills.push((victim, carried_by.owner, location.clone()));
                 }
             }
+        }
 
-            for (stabbing_victim, _) in stabbings.iter() {
-                // Insert a CarriedBy component for the item
-                delete_player(&entities, &carried_bys, *stabbing_victim);
+        for (victim, killer, location) in kills {
+            deads
+                .insert(
+                    victim,
+                    Dead {
+                        respawn_ticks: RESPAWN_TICKS,
+                    },
+                )
+                .expect("Failed to kill player");
+            move_tos.remove(victim);
+
+            // Leave everything they were carrying where they died
+            let dropped: Vec<(Entity, Item)> = (&entities, &carried_bys, &items)
+                .join()
+                .filter(|(_, carried_by, _)| carried_by.owner == victim)
+                .map(|(item_entity, _, item)| (item_entity, item.clone()))
+                .collect();
+            for (item_entity, item) in dropped {
+                drop_item(
+                    item_entity,
+                    &item,
+                    &mut carried_bys,
+                    &mut pickups,
+                    &mut renders,
+                    &mut equippeds,
+                );
             }
-        }
 
-        for (_, victim_location) in stabbings {
-            // Add a blood splatter to highlight what happened here
-            create_blood_splatter(&mut ecs, victim_location);
+            events.single_write(GameEvent::PlayerKilled {
+                victim,
+                killer,
+                location,
+            });
         }
     }
 }